@@ -0,0 +1,431 @@
+use crate::settings::AppSettings;
+use crate::AppEvent;
+use anyhow::{Context, Result};
+use flume::{Receiver, Sender};
+#[allow(unused_imports)]
+use log::{error, info, trace, warn};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::thread;
+use std::thread::{sleep, JoinHandle};
+use std::time::Duration;
+use wooting_analog_midi_core::{
+  ArpConfig, Channel, DeviceID, DeviceInfo, DeviceProfile, HIDCodes, InputConfig, KeyConfig,
+  KeyMappings, MidiService, ModifierConfig, MpeConfig, NoteConfig, NoteID, OutputConfig,
+  PortOption, ScaleConfig, ServiceEvent, WootingAnalogResult, REFRESH_RATE,
+};
+
+// This defines the rate at which midi updates are sent to the UI
+pub const MIDI_UPDATE_RATE: u32 = 30; //Hz
+
+// How many polls there are between checking for changes to the output ports
+const PORT_REFRESH_INTERVAL: u32 = REFRESH_RATE as u32;
+
+#[derive(Serialize, Debug)]
+pub struct MidiEntry {
+  note: NoteID,
+  velocity: f32,
+  channel: Channel,
+  pressed: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MidiUpdateEntry {
+  value: f32,
+  notes: Vec<MidiEntry>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MidiUpdate {
+  data: HashMap<u8, MidiUpdateEntry>,
+}
+
+// Requests handled by the engine thread, each carries the sender its response goes to
+enum EngineCommand {
+  UpdateMapping {
+    mapping: KeyMappings,
+    device_profiles: HashMap<DeviceID, DeviceProfile>,
+    reply: Sender<Result<()>>,
+  },
+  SetKeyConfigs(HashMap<HIDCodes, KeyConfig>, Sender<()>),
+  SetConfig {
+    modifiers: Vec<ModifierConfig>,
+    note_config: NoteConfig,
+    mpe: MpeConfig,
+    arp: ArpConfig,
+    software_sustain: bool,
+    scale: ScaleConfig,
+    reply: Sender<Result<()>>,
+  },
+  // Both reply with the new port options and the outputs that are now configured
+  AddOutput(
+    OutputConfig,
+    Sender<Result<(Vec<PortOption>, Vec<OutputConfig>)>>,
+  ),
+  RemoveOutput(String, Sender<Result<(Vec<PortOption>, Vec<OutputConfig>)>>),
+  GetPortOptions(Sender<Vec<PortOption>>),
+  SetInput(Option<InputConfig>, Sender<Result<()>>),
+  GetInputOptions(Sender<Result<Vec<String>>>),
+  GetConnectedDevices(Sender<Result<Vec<DeviceInfo>>>),
+  Snapshot(Sender<MidiUpdate>),
+}
+
+// Handle to the thread which owns the `MidiService`. The service (and with it the MIDI connection and key state) never leaves that thread, everything else talks to it through commands
+pub struct Engine {
+  commands: Option<Sender<EngineCommand>>,
+  thread: Option<JoinHandle<()>>,
+}
+
+impl Engine {
+  // Starts the engine thread with the given settings, returning once the service has been initialised along with the number of connected devices
+  pub fn start(settings: AppSettings, events: Sender<AppEvent>) -> Result<(Engine, u32)> {
+    let (command_tx, command_rx) = flume::unbounded::<EngineCommand>();
+    let (init_tx, init_rx) = flume::bounded::<Result<u32>>(1);
+
+    let thread = thread::spawn(move || {
+      let mut service = MidiService::new();
+      let device_count = match Self::init_service(&mut service, &settings) {
+        Ok(device_count) => device_count,
+        Err(e) => {
+          init_tx.send(Err(e)).ok();
+          return;
+        }
+      };
+      init_tx.send(Ok(device_count)).ok();
+
+      EngineThread {
+        service,
+        commands: command_rx,
+        events,
+        has_devices: device_count > 0,
+      }
+      .run();
+    });
+
+    let device_count = init_rx
+      .recv()
+      .context("Engine thread stopped during initialisation")??;
+
+    Ok((
+      Engine {
+        commands: Some(command_tx),
+        thread: Some(thread),
+      },
+      device_count,
+    ))
+  }
+
+  fn init_service(service: &mut MidiService, settings: &AppSettings) -> Result<u32> {
+    service
+      .update_mapping(&settings.get_proper_mapping())
+      .with_context(|| "Failed to initialise loaded mapping")?;
+    service
+      .update_device_profiles(&settings.get_device_profiles())
+      .with_context(|| "Failed to initialise device mappings")?;
+    service.update_key_configs(&settings.get_key_configs());
+    service.set_modifiers(&settings.get_modifiers());
+    service.set_note_config(settings.note_config.clone());
+    service.set_scale(settings.scale.clone());
+    service.set_mpe(&settings.mpe)?;
+    service.set_arp(&settings.arp)?;
+    service.set_software_sustain(settings.software_sustain)?;
+    service.set_output_configs(settings.outputs.clone());
+    let device_count = service.init()?;
+    // The input is optional, so the app can run without it
+    if let Err(e) = service.set_input(settings.input.clone()) {
+      warn!("Failed to connect MIDI input: {}", e);
+    }
+    Ok(device_count)
+  }
+
+  fn request<T>(&self, command: impl FnOnce(Sender<T>) -> EngineCommand) -> Result<T> {
+    let (reply_tx, reply_rx) = flume::bounded(1);
+    self
+      .commands
+      .as_ref()
+      .context("Engine has been stopped")?
+      .send(command(reply_tx))
+      .map_err(|_| anyhow!("Engine thread is no longer running"))?;
+    reply_rx
+      .recv()
+      .context("Engine thread stopped before responding")
+  }
+
+  pub fn update_mapping(&self, settings: &AppSettings) -> Result<()> {
+    let mapping = settings.get_proper_mapping();
+    let device_profiles = settings.get_device_profiles();
+    self.request(|reply| EngineCommand::UpdateMapping {
+      mapping,
+      device_profiles,
+      reply,
+    })?
+  }
+
+  pub fn set_key_configs(&self, settings: &AppSettings) -> Result<()> {
+    let key_configs = settings.get_key_configs();
+    self.request(|reply| EngineCommand::SetKeyConfigs(key_configs, reply))
+  }
+
+  pub fn set_config(&self, settings: &AppSettings) -> Result<()> {
+    let modifiers = settings.get_modifiers();
+    let note_config = settings.note_config.clone();
+    let mpe = settings.mpe.clone();
+    let arp = settings.arp.clone();
+    let software_sustain = settings.software_sustain;
+    let scale = settings.scale.clone();
+    self.request(|reply| EngineCommand::SetConfig {
+      modifiers,
+      note_config,
+      mpe,
+      arp,
+      software_sustain,
+      scale,
+      reply,
+    })?
+  }
+
+  pub fn add_output(&self, config: OutputConfig) -> Result<(Vec<PortOption>, Vec<OutputConfig>)> {
+    self.request(|reply| EngineCommand::AddOutput(config, reply))?
+  }
+
+  pub fn remove_output(&self, port: String) -> Result<(Vec<PortOption>, Vec<OutputConfig>)> {
+    self.request(|reply| EngineCommand::RemoveOutput(port, reply))?
+  }
+
+  pub fn get_port_options(&self) -> Result<Vec<PortOption>> {
+    self.request(EngineCommand::GetPortOptions)
+  }
+
+  pub fn set_input(&self, config: Option<InputConfig>) -> Result<()> {
+    self.request(|reply| EngineCommand::SetInput(config, reply))?
+  }
+
+  pub fn get_input_options(&self) -> Result<Vec<String>> {
+    self.request(EngineCommand::GetInputOptions)?
+  }
+
+  pub fn get_connected_devices(&self) -> Result<Vec<DeviceInfo>> {
+    self.request(EngineCommand::GetConnectedDevices)?
+  }
+
+  pub fn snapshot(&self) -> Result<MidiUpdate> {
+    self.request(EngineCommand::Snapshot)
+  }
+
+  // Stops the engine thread and waits for it to uninitialise the service
+  pub fn stop(&mut self) {
+    // Dropping the sender disconnects the command channel, which is what tells the thread to stop
+    self.commands = None;
+    if let Some(thread) = self.thread.take() {
+      trace!("waiting for engine thread");
+      if let Err(e) = thread.join() {
+        error!("Error joining engine thread: {:?}", e);
+      }
+    }
+  }
+}
+
+impl Drop for Engine {
+  fn drop(&mut self) {
+    self.stop();
+  }
+}
+
+struct EngineThread {
+  service: MidiService,
+  commands: Receiver<EngineCommand>,
+  events: Sender<AppEvent>,
+  has_devices: bool,
+}
+
+impl EngineThread {
+  fn run(mut self) {
+    let mut iter_count: u32 = 0;
+    'engine: loop {
+      loop {
+        match self.commands.try_recv() {
+          Ok(command) => self.handle_command(command),
+          Err(flume::TryRecvError::Empty) => break,
+          Err(flume::TryRecvError::Disconnected) => break 'engine,
+        }
+      }
+
+      if iter_count % PORT_REFRESH_INTERVAL == 0 {
+        self.refresh_ports();
+      }
+
+      let errored = !self.poll();
+
+      if !errored && (iter_count % (REFRESH_RATE as u32 / MIDI_UPDATE_RATE)) == 0 {
+        if let Err(e) = self.events.send(AppEvent::MidiUpdate(self.snapshot())) {
+          error!("Error while sending App Update, {:#?}", e);
+        }
+      }
+
+      iter_count += 1;
+      sleep(Duration::from_secs_f32(1.0 / REFRESH_RATE))
+    }
+    self.service.uninit();
+    trace!("Engine thread stopped");
+  }
+
+  fn handle_command(&mut self, command: EngineCommand) {
+    // A failed send only means the requester isn't waiting for the response anymore
+    match command {
+      EngineCommand::UpdateMapping {
+        mapping,
+        device_profiles,
+        reply,
+      } => {
+        let result = self
+          .service
+          .update_mapping(&mapping)
+          .and_then(|_| self.service.update_device_profiles(&device_profiles));
+        reply.send(result).ok();
+      }
+      EngineCommand::SetKeyConfigs(key_configs, reply) => {
+        self.service.update_key_configs(&key_configs);
+        reply.send(()).ok();
+      }
+      EngineCommand::SetConfig {
+        modifiers,
+        note_config,
+        mpe,
+        arp,
+        software_sustain,
+        scale,
+        reply,
+      } => {
+        self.service.set_modifiers(&modifiers);
+        self.service.set_note_config(note_config);
+        self.service.set_scale(scale);
+        let result = self
+          .service
+          .set_mpe(&mpe)
+          .and_then(|_| self.service.set_arp(&arp))
+          .and_then(|_| self.service.set_software_sustain(software_sustain));
+        reply.send(result).ok();
+      }
+      EngineCommand::AddOutput(config, reply) => {
+        let result = self
+          .service
+          .add_output_port(config)
+          .map(|_| (self.port_options(), self.service.output_configs()));
+        reply.send(result).ok();
+      }
+      EngineCommand::RemoveOutput(port, reply) => {
+        let result = self
+          .service
+          .remove_output_port(&port)
+          .map(|_| (self.port_options(), self.service.output_configs()));
+        reply.send(result).ok();
+      }
+      EngineCommand::GetPortOptions(reply) => {
+        reply.send(self.port_options()).ok();
+      }
+      EngineCommand::SetInput(config, reply) => {
+        reply.send(self.service.set_input(config)).ok();
+      }
+      EngineCommand::GetInputOptions(reply) => {
+        reply.send(self.service.get_input_options()).ok();
+      }
+      EngineCommand::GetConnectedDevices(reply) => {
+        reply.send(self.service.get_connected_devices()).ok();
+      }
+      EngineCommand::Snapshot(reply) => {
+        reply.send(self.snapshot()).ok();
+      }
+    }
+  }
+
+  fn refresh_ports(&mut self) {
+    match self.service.refresh_ports() {
+      Ok(true) => {
+        if let Err(e) = self
+          .events
+          .send(AppEvent::PortOptions(self.port_options()))
+          .context("Error when sending PortOptions event!")
+        {
+          crate::output_err(e);
+        }
+      }
+      Ok(false) => {}
+      Err(e) => error!("Failed to refresh output ports: {}", e),
+    }
+  }
+
+  fn port_options(&self) -> Vec<PortOption> {
+    self
+      .service
+      .port_options
+      .as_ref()
+      .cloned()
+      .unwrap_or(vec![])
+  }
+
+  // Returns whether the poll succeeded
+  fn poll(&mut self) -> bool {
+    let result = self.service.poll();
+    for event in self.service.take_events() {
+      let app_event = match event {
+        ServiceEvent::DeviceConnected(device) => {
+          self.has_devices = true;
+          AppEvent::DeviceConnected(device.into())
+        }
+        ServiceEvent::DeviceDisconnected(device) => AppEvent::DeviceDisconnected(device.into()),
+      };
+      if let Err(e) = self
+        .events
+        .send(app_event)
+        .context("Error when sending device event!")
+      {
+        crate::output_err(e);
+      }
+    }
+
+    if let Err(e) = result {
+      match e.root_cause().downcast_ref::<WootingAnalogResult>() {
+        Some(WootingAnalogResult::NoDevices) => {
+          if self.has_devices {
+            self.has_devices = false;
+            warn!("{}", WootingAnalogResult::NoDevices);
+            if let Err(e) = self
+              .events
+              .send(AppEvent::NoDevices)
+              .context("Error when sending NoDevices event!")
+            {
+              crate::output_err(e);
+            }
+          }
+        }
+        Some(_) | None => {
+          error!("{}", e);
+        }
+      };
+      false
+    } else {
+      true
+    }
+  }
+
+  fn snapshot(&self) -> MidiUpdate {
+    // Keys are merged across devices, so the UI shows the combined state
+    let mut data: HashMap<u8, MidiUpdateEntry> = HashMap::new();
+    for (_, key_id, key) in self.service.keys() {
+      if !key.notes.is_empty() || key.current_value > 0.0 {
+        let entry = data.entry(*key_id as u8).or_insert(MidiUpdateEntry {
+          value: 0.0,
+          notes: vec![],
+        });
+        entry.value = entry.value.max(key.current_value);
+        entry.notes.extend(key.notes.iter().map(|note| MidiEntry {
+          note: note.note_id,
+          velocity: note.velocity,
+          channel: note.channel,
+          pressed: note.pressed,
+        }));
+      }
+    }
+    MidiUpdate { data }
+  }
+}
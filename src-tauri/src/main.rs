@@ -13,49 +13,30 @@ extern crate lazy_static;
 extern crate anyhow;
 
 use log::*;
+use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, RwLock};
 use std::thread;
-use std::thread::{sleep, JoinHandle};
+use std::thread::JoinHandle;
 use wooting_analog_midi_core::{
-  Channel, DeviceInfo, MidiService, NoteID, PortOption, WootingAnalogResult, REFRESH_RATE,
+  Channel, DeviceID, DeviceInfo, DeviceType, KeyConfig, LayoutConfig, OutputConfig, PortOption,
 };
+mod engine;
 mod settings;
 use anyhow::{Context, Result};
+use engine::{Engine, MidiUpdate};
 use flume::Receiver;
 use serde::Serialize;
-use settings::AppSettings;
-use std::collections::HashMap;
+use settings::{AppSettings, DeviceSettings, KeyMapping};
 use std::time::{Duration, Instant};
 use tauri::{Manager, Menu, MenuItem, Submenu};
 
-// This defines the rate at which midi updates are sent to the UI
-pub const MIDI_UPDATE_RATE: u32 = 30; //Hz
 const SAVE_THROTTLE: Duration = Duration::from_secs(5);
 
-#[derive(Serialize, Debug)]
-struct MidiEntry {
-  note: NoteID,
-  velocity: f32,
-  channel: Channel,
-  pressed: bool,
-}
-
-#[derive(Serialize, Debug)]
-struct MidiUpdateEntry {
-  value: f32,
-  notes: Vec<MidiEntry>,
-}
-
-#[derive(Serialize, Debug)]
-pub struct MidiUpdate {
-  data: HashMap<u8, MidiUpdateEntry>,
-}
-
 struct App {
   settings: AppSettings,
   thread_pool: Vec<JoinHandle<()>>,
-  midi_service: Arc<RwLock<MidiService>>,
+  engine: Option<Engine>,
   running: Arc<AtomicBool>,
   last_save: Option<Instant>,
   event_receiver: Option<flume::Receiver<AppEvent>>,
@@ -66,7 +47,7 @@ impl App {
     App {
       settings: AppSettings::default(),
       thread_pool: vec![],
-      midi_service: Arc::new(RwLock::new(MidiService::new())),
+      engine: None,
       running: Arc::new(AtomicBool::new(true)),
       last_save: None,
       event_receiver: None,
@@ -75,150 +56,20 @@ impl App {
 
   fn init(&mut self) -> Result<()> {
     self.settings = AppSettings::load_config().context("Failed to load App Settings")?;
-    {
-      let mut midi = self.midi_service.write().unwrap();
-      midi
-        .update_mapping(&self.settings.get_proper_mapping())
-        .with_context(|| "Failed to initialise loaded mapping")?;
-      midi.amount_to_shift = self.settings.shift_amount;
-    }
-
-    let device_count = self.midi_service.write().unwrap().init()?;
-
-    let mut has_devices: bool = device_count > 0;
 
     let (tx, rx) = flume::unbounded::<AppEvent>();
-
-    let running_inner = self.running.clone();
-    let midi_service_inner = self.midi_service.clone();
-    let tx_inner = tx.clone();
-
-    self.thread_pool.push(thread::spawn(move || {
-      let mut iter_count: u32 = 0;
-      // if has_devices {
-      //   let devices = midi_service_inner
-      //     .read()
-      //     .unwrap()
-      //     .get_connected_devices()
-      //     .context("Failed to get connected devices")
-      //     .map_err(output_err)
-      //     .unwrap_or(vec![]);
-      //   if let Err(e) = tx_inner
-      //     .send(AppEvent::FoundDevices(devices))
-      //     .context("Error when sending FoundDevices event!")
-      //   {
-      //     output_err(e);
-      //   }
-      // }
-
-      // if let Err(e) = tx_inner
-      //   .send(AppEvent::PortOptions(
-      //     midi_service_inner
-      //       .read()
-      //       .unwrap()
-      //       .port_options
-      //       .as_ref()
-      //       .cloned()
-      //       .expect("There should be at least some port options"),
-      //   ))
-      //   .context("Error when sending FoundDevices event!")
-      // {
-      //   output_err(e);
-      // }
-
-      while running_inner.load(Ordering::SeqCst) {
-        let mut errored = false;
-        // We have to do this hacky structure to ensure the write lock gets dropped before the read lock later on
-        {
-          let result = midi_service_inner.write().unwrap().poll();
-          if let Err(e) = result
-          // .map_err(output_err)
-          {
-            errored = true;
-            match e.root_cause().downcast_ref::<WootingAnalogResult>() {
-              Some(WootingAnalogResult::NoDevices) => {
-                if has_devices {
-                  has_devices = false;
-                  warn!("{}", WootingAnalogResult::NoDevices);
-                  if let Err(e) = tx_inner
-                    .send(AppEvent::NoDevices)
-                    .context("Error when sending NoDevices event!")
-                  {
-                    output_err(e);
-                  }
-                }
-              }
-              Some(_) | None => {
-                error!("{}", e);
-              }
-            };
-          }
-        }
-
-        if !errored {
-          // This should be replaced with a handler for the device disconnected/connected events to dynamically update the UI
-          if !has_devices {
-            let devices = midi_service_inner
-              .read()
-              .unwrap()
-              .get_connected_devices()
-              .context("Failed to get connected devices")
-              .map_err(output_err)
-              .unwrap_or(vec![]);
-            if let Err(e) = tx_inner
-              .send(AppEvent::FoundDevices(devices))
-              .context("Error when sending FoundDevices event!")
-            {
-              output_err(e);
-            } else {
-              has_devices = true;
-            }
-          }
-
-          if (iter_count % (REFRESH_RATE as u32 / MIDI_UPDATE_RATE)) == 0 {
-            let keys = &midi_service_inner.read().unwrap().keys;
-            let event_message = MidiUpdate {
-              data: keys
-                .iter()
-                .filter_map(|(key_id, key)| {
-                  if key.notes.len() > 0 || key.current_value > 0.0 {
-                    Some((
-                      key_id.clone() as u8,
-                      MidiUpdateEntry {
-                        value: key.current_value,
-                        notes: key
-                          .notes
-                          .iter()
-                          .map(|note| MidiEntry {
-                            note: note.note_id,
-                            velocity: note.velocity,
-                            channel: note.channel,
-                            pressed: note.pressed,
-                          })
-                          .collect(),
-                      },
-                    ))
-                  } else {
-                    None
-                  }
-                })
-                .collect(),
-            };
-            if let Err(e) = tx_inner.send(AppEvent::MidiUpdate(event_message)) {
-              error!("Error while sending App Update, {:#?}", e);
-            }
-          }
-        }
-
-        iter_count += 1;
-        sleep(Duration::from_secs_f32(1.0 / REFRESH_RATE))
-      }
-    }));
+    let (engine, device_count) = Engine::start(self.settings.clone(), tx)?;
+    info!("Engine started with {} devices", device_count);
+    self.engine = Some(engine);
 
     self.event_receiver = Some(rx);
     Ok(())
   }
 
+  fn engine(&self) -> Result<&Engine> {
+    self.engine.as_ref().context("Engine not initialised")
+  }
+
   fn listen(&mut self) -> Result<Receiver<AppEvent>> {
     self
       .event_receiver
@@ -226,18 +77,62 @@ impl App {
       .ok_or_else(|| anyhow!("Failed to retrieve event listener"))
   }
 
-  fn update_config(&mut self, config: AppSettings) {
+  fn update_config(&mut self, mut config: AppSettings) {
+    // Outputs are changed through `add_output` & `remove_output`, so the UI's copy of the settings may not have the latest ones
+    config.outputs = self.settings.outputs.take();
+    let input_changed = config.input != self.settings.input;
     self.settings = config;
-    {
-      let mut midi = self.midi_service.write().unwrap();
+    if let Ok(engine) = self.engine().map_err(output_err) {
+      if input_changed {
+        if let Err(e) = engine.set_input(self.settings.input.clone()) {
+          error!("Error connecting MIDI input! {:#?}", e);
+        }
+      }
       //Update the service with the new mapping
-      if let Err(e) = midi.update_mapping(&self.settings.get_proper_mapping()) {
+      if let Err(e) = engine.update_mapping(&self.settings) {
         error!("Error updating midi service mapping! {:#?}", e);
       }
-      midi.amount_to_shift = self.settings.shift_amount;
-      midi.set_note_config(self.settings.note_config.clone());
+      if let Err(e) = engine
+        .set_key_configs(&self.settings)
+        .and_then(|_| engine.set_config(&self.settings))
+      {
+        error!("Error updating midi service config! {:#?}", e);
+      }
+    }
+    self.save_config();
+  }
+
+  fn set_key_config(&mut self, key: u8, config: KeyConfig) -> AppSettings {
+    if config.is_empty() {
+      self.settings.key_configs.remove(&key);
+    } else {
+      self.settings.key_configs.insert(key, config);
+    }
+    if let Err(e) = self
+      .engine()
+      .and_then(|engine| engine.set_key_configs(&self.settings))
+    {
+      error!("Error updating key configs! {:#?}", e);
+    }
+    self.save_config();
+    self.settings.clone()
+  }
+
+  fn set_device_settings(&mut self, device_id: DeviceID, settings: DeviceSettings) -> AppSettings {
+    if settings.is_empty() {
+      self.settings.devices.remove(&device_id);
+    } else {
+      self.settings.devices.insert(device_id, settings);
+    }
+    // The device profiles are sent along with the mapping
+    if let Err(e) = self
+      .engine()
+      .and_then(|engine| engine.update_mapping(&self.settings))
+    {
+      error!("Error updating device settings! {:#?}", e);
     }
     self.save_config();
+    self.settings.clone()
   }
 
   fn save_config(&mut self) {
@@ -252,26 +147,35 @@ impl App {
 
   fn get_port_options(&self) -> Vec<PortOption> {
     self
-      .midi_service
-      .read()
-      .unwrap()
-      .port_options
-      .as_ref()
-      .cloned()
+      .engine()
+      .and_then(|engine| engine.get_port_options())
+      .map_err(output_err)
       .unwrap_or(vec![])
   }
 
-  fn get_connected_devices(&self) -> Vec<DeviceInfo> {
+  fn get_connected_devices(&self) -> Vec<DeviceDetails> {
     self
-      .midi_service
-      .read()
-      .unwrap()
-      .get_connected_devices()
+      .engine()
+      .and_then(|engine| engine.get_connected_devices())
       .context("Failed to get connected devices")
       .map_err(output_err)
+      .map(|devices| devices.into_iter().map(DeviceDetails::from).collect())
       .unwrap_or(vec![])
   }
 
+  fn get_input_options(&self) -> Vec<String> {
+    self
+      .engine()
+      .and_then(|engine| engine.get_input_options())
+      .context("Failed to get input options")
+      .map_err(output_err)
+      .unwrap_or(vec![])
+  }
+
+  fn get_midi_state(&self) -> Result<MidiUpdate> {
+    self.engine()?.snapshot()
+  }
+
   fn exec_loop<F: 'static>(&mut self, mut f: F)
   where
     F: FnMut() + Send,
@@ -284,18 +188,18 @@ impl App {
     }));
   }
 
-  fn select_port(&mut self, option: usize) -> Result<Vec<PortOption>> {
-    self.midi_service.write().unwrap().select_port(option)?;
-    Ok(
-      self
-        .midi_service
-        .read()
-        .unwrap()
-        .port_options
-        .as_ref()
-        .cloned()
-        .unwrap_or(vec![]),
-    )
+  fn add_output(&mut self, config: OutputConfig) -> Result<Vec<PortOption>> {
+    let (options, outputs) = self.engine()?.add_output(config)?;
+    self.settings.outputs = Some(outputs);
+    self.save_config();
+    Ok(options)
+  }
+
+  fn remove_output(&mut self, port: String) -> Result<Vec<PortOption>> {
+    let (options, outputs) = self.engine()?.remove_output(port)?;
+    self.settings.outputs = Some(outputs);
+    self.save_config();
+    Ok(options)
   }
 
   fn uninit(&mut self) {
@@ -305,13 +209,16 @@ impl App {
 
     trace!("waiting for thread");
     self.running.store(false, Ordering::SeqCst);
+    // Stopping the engine disconnects the event channel, which lets the event thread finish
+    if let Some(mut engine) = self.engine.take() {
+      engine.stop();
+    }
     for thread in self.thread_pool.drain(..) {
       if let Err(e) = thread.join() {
         error!("Error joining thread: {:?}", e);
       }
     }
     trace!("thread wait done");
-    self.midi_service.write().unwrap().uninit();
   }
 }
 
@@ -330,13 +237,44 @@ fn output_err(error: anyhow::Error) -> anyhow::Error {
   error
 }
 
+// A device as the UI sees it. Device IDs are 64 bit, which is more than a JS number can hold exactly, so they're sent as strings
+#[derive(Serialize, Debug, Clone)]
+pub struct DeviceDetails {
+  vendor_id: u16,
+  product_id: u16,
+  manufacturer_name: String,
+  device_name: String,
+  device_id: String,
+  device_type: DeviceType,
+}
+
+impl From<DeviceInfo> for DeviceDetails {
+  fn from(info: DeviceInfo) -> Self {
+    DeviceDetails {
+      vendor_id: info.vendor_id,
+      product_id: info.product_id,
+      manufacturer_name: info.manufacturer_name,
+      device_name: info.device_name,
+      device_id: info.device_id.to_string(),
+      device_type: info.device_type,
+    }
+  }
+}
+
+fn parse_device_id(device_id: &str) -> Result<DeviceID> {
+  device_id
+    .parse()
+    .with_context(|| format!("Invalid device ID '{}'", device_id))
+}
+
 #[derive(Serialize, Debug)]
 #[serde(tag = "type", content = "value")]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum AppEvent {
   MidiUpdate(MidiUpdate),
   NoDevices,
-  FoundDevices(Vec<DeviceInfo>),
+  DeviceConnected(DeviceDetails),
+  DeviceDisconnected(DeviceDetails),
   PortOptions(Vec<PortOption>),
 }
 
@@ -379,19 +317,94 @@ fn update_config(config: AppSettings) {
   APP.write().unwrap().update_config(config);
 }
 
+#[tauri::command]
+fn get_key_config(key: u8) -> KeyConfig {
+  APP
+    .read()
+    .unwrap()
+    .settings
+    .key_configs
+    .get(&key)
+    .cloned()
+    .unwrap_or_default()
+}
+
+#[tauri::command]
+fn set_key_config(key: u8, config: KeyConfig) -> AppSettings {
+  APP.write().unwrap().set_key_config(key, config)
+}
+
+#[tauri::command]
+fn get_device_settings(device: String) -> Result<DeviceSettings, CommandError> {
+  let device_id = parse_device_id(&device)?;
+  Ok(
+    APP
+      .read()
+      .unwrap()
+      .settings
+      .devices
+      .get(&device_id)
+      .cloned()
+      .unwrap_or_default(),
+  )
+}
+
+#[tauri::command]
+fn set_device_settings(
+  device: String,
+  settings: DeviceSettings,
+) -> Result<AppSettings, CommandError> {
+  let device_id = parse_device_id(&device)?;
+  Ok(
+    APP
+      .write()
+      .unwrap()
+      .set_device_settings(device_id, settings),
+  )
+}
+
 #[tauri::command]
 fn get_port_options() -> Vec<PortOption> {
   APP.write().unwrap().get_port_options()
 }
 
 #[tauri::command]
-fn get_connected_devices() -> Vec<DeviceInfo> {
+fn get_connected_devices() -> Vec<DeviceDetails> {
   APP.write().unwrap().get_connected_devices()
 }
 
 #[tauri::command]
-fn select_port(option: usize) -> Result<Vec<PortOption>, CommandError> {
-  Ok(APP.write().unwrap().select_port(option)?)
+fn get_input_options() -> Vec<String> {
+  APP.read().unwrap().get_input_options()
+}
+
+#[tauri::command]
+fn get_midi_state() -> Result<MidiUpdate, CommandError> {
+  Ok(APP.read().unwrap().get_midi_state()?)
+}
+
+#[tauri::command]
+fn generate_keymapping(
+  layout: LayoutConfig,
+  channel: Channel,
+) -> Result<HashMap<Channel, Vec<KeyMapping>>, CommandError> {
+  Ok(
+    APP
+      .read()
+      .unwrap()
+      .settings
+      .generate_keymapping(&layout, channel)?,
+  )
+}
+
+#[tauri::command]
+fn add_output(config: OutputConfig) -> Result<Vec<PortOption>, CommandError> {
+  Ok(APP.write().unwrap().add_output(config)?)
+}
+
+#[tauri::command]
+fn remove_output(port: String) -> Result<Vec<PortOption>, CommandError> {
+  Ok(APP.write().unwrap().remove_output(port)?)
 }
 
 fn main_menu() -> Menu {
@@ -433,9 +446,17 @@ fn main() -> Result<()> {
     .invoke_handler(tauri::generate_handler![
       get_config,
       update_config,
+      get_key_config,
+      set_key_config,
+      get_device_settings,
+      set_device_settings,
       get_port_options,
-      select_port,
-      get_connected_devices
+      add_output,
+      remove_output,
+      get_connected_devices,
+      get_input_options,
+      get_midi_state,
+      generate_keymapping
     ])
     .setup(|app| {
       #[cfg(debug_assertions)]
@@ -8,20 +8,98 @@ use std::fs::OpenOptions;
 use std::io::{Read, Write};
 use std::path::PathBuf;
 use tauri::api::path::config_dir;
-use wooting_analog_midi_core::{Channel, FromPrimitive, HIDCodes, NoteConfig, NoteID};
+use wooting_analog_midi_core::{
+  ArpConfig, Channel, DeviceID, DeviceProfile, FromPrimitive, HIDCodes, InputConfig, KeyConfig,
+  KeyMappings, LayoutConfig, Mapping, ModifierConfig, MpeConfig, NoteConfig, NoteID, OutputConfig,
+  OutputRouting, ScaleConfig,
+};
 
 fn default_shift_amount() -> i8 {
   12
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum KeyMapping {
+  // Plain (key, note) pair, this is the original format so older configs still load
+  Note(u8, NoteID),
+  Mapping {
+    key: u8,
+    #[serde(flatten)]
+    mapping: Mapping,
+  },
+}
+
+impl KeyMapping {
+  pub fn key(&self) -> u8 {
+    match self {
+      KeyMapping::Note(key, _) => *key,
+      KeyMapping::Mapping { key, .. } => *key,
+    }
+  }
+
+  pub fn mapping(&self) -> Mapping {
+    match self {
+      KeyMapping::Note(_, note) => Mapping::Note { note: *note },
+      KeyMapping::Mapping { mapping, .. } => mapping.clone(),
+    }
+  }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DeviceSettings {
+  // Replaces the global keymapping for this device
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub keymapping: Option<HashMap<Channel, Vec<KeyMapping>>>,
+  // Sends everything from this device on the given channel
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub channel: Option<Channel>,
+  #[serde(default)]
+  pub transpose: i8,
+}
+
+impl DeviceSettings {
+  pub fn is_empty(&self) -> bool {
+    self.keymapping.is_none() && self.channel.is_none() && self.transpose == 0
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct AppSettings {
-  //Channel -> [(key, note)]
-  pub keymapping: HashMap<Channel, Vec<(u8, NoteID)>>,
+  //Channel -> [(key, note) | {key, type, ...}]
+  pub keymapping: HashMap<Channel, Vec<KeyMapping>>,
+  // Shift applied by Left Shift when no `modifiers` have been configured
   #[serde(default = "default_shift_amount")]
   pub shift_amount: i8,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub modifiers: Option<Vec<ModifierConfig>>,
   #[serde(default)]
   pub note_config: NoteConfig,
+  //key -> overrides of note_config
+  #[serde(default)]
+  pub key_configs: HashMap<u8, KeyConfig>,
+  //device id -> settings for that device
+  #[serde(default)]
+  pub devices: HashMap<DeviceID, DeviceSettings>,
+  // Ports to output to whenever they're available, the first port is used when this hasn't been set
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub outputs: Option<Vec<OutputConfig>>,
+  // Single port from before `outputs`, only read so older configs keep outputting to it
+  #[serde(default, skip_serializing)]
+  preferred_port: Option<String>,
+  #[serde(default)]
+  pub mpe: MpeConfig,
+  #[serde(default)]
+  pub arp: ArpConfig,
+  // Holds back note offs while a sustain key is down, for synths which ignore the sustain pedal
+  #[serde(default)]
+  pub software_sustain: bool,
+  // Key which the scale degree mappings are played in
+  #[serde(default)]
+  pub scale: ScaleConfig,
+  // External MIDI input which gets merged into the outputs
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub input: Option<InputConfig>,
 }
 
 const CONFIG_DIR: &str = "wooting-midi";
@@ -50,7 +128,7 @@ impl AppSettings {
       Self::save_default(&mut file)
     } else {
       match serde_json::from_str::<AppSettings>(&content.trim()[..]) {
-        Ok(res) => Ok(res),
+        Ok(res) => Ok(res.migrate()),
         Err(e) => {
           error!(
             "Error deserializing settings, {}.\nSaving default config...",
@@ -63,6 +141,18 @@ impl AppSettings {
     }
   }
 
+  fn migrate(mut self) -> Self {
+    if let Some(port) = self.preferred_port.take() {
+      if self.outputs.is_none() {
+        self.outputs = Some(vec![OutputConfig {
+          port,
+          routing: OutputRouting::All,
+        }]);
+      }
+    }
+    self
+  }
+
   fn save_default(file: &mut std::fs::File) -> Result<AppSettings> {
     let default = Self::default();
     file.write_all(&serde_json::to_vec(&default)?[..])?;
@@ -81,36 +171,90 @@ impl AppSettings {
     file.write_all(&serde_json::to_vec(&self)?[..])?;
     Ok(())
   }
-  // Channel -> [(key, note)] => key -> [(channel, note)]
-  pub fn get_proper_mapping(&self) -> HashMap<HIDCodes, Vec<(Channel, NoteID)>> {
-    let mut mapping = HashMap::new();
-
-    for (chan, mappings) in self.keymapping.iter() {
-      for (key, note) in mappings.iter() {
-        if let Some(hid_key) = HIDCodes::from_u8(*key) {
-          // Try and get the vec if it already is present, if not creat it
-          let key_mappings = {
-            if let Some(m) = mapping.get_mut(&hid_key) {
-              m
-            } else {
-              mapping.insert(hid_key.clone(), vec![]);
-              mapping.get_mut(&hid_key).unwrap()
-            }
-          };
-
-          key_mappings.push((chan.clone(), note.clone()));
+  pub fn get_proper_mapping(&self) -> KeyMappings {
+    Self::convert_mapping(&self.keymapping, None)
+  }
+
+  pub fn get_device_profiles(&self) -> HashMap<DeviceID, DeviceProfile> {
+    self
+      .devices
+      .iter()
+      .map(|(device_id, settings)| {
+        let mapping = match (&settings.keymapping, settings.channel) {
+          (Some(keymapping), channel) => Some(Self::convert_mapping(keymapping, channel)),
+          (None, Some(channel)) => Some(Self::convert_mapping(&self.keymapping, Some(channel))),
+          (None, None) => None,
+        };
+        (
+          *device_id,
+          DeviceProfile {
+            mapping,
+            transpose: settings.transpose,
+          },
+        )
+      })
+      .collect()
+  }
+
+  // Channel -> [(key, mapping)] => key -> [(channel, mapping)]
+  fn convert_mapping(
+    keymapping: &HashMap<Channel, Vec<KeyMapping>>,
+    channel_override: Option<Channel>,
+  ) -> KeyMappings {
+    let mut mapping: KeyMappings = HashMap::new();
+
+    for (chan, mappings) in keymapping.iter() {
+      let chan = channel_override.unwrap_or(*chan);
+      for key_mapping in mappings.iter() {
+        if let Some(hid_key) = HIDCodes::from_u8(key_mapping.key()) {
+          mapping
+            .entry(hid_key)
+            .or_insert_with(Vec::new)
+            .push((chan, key_mapping.mapping()));
         }
       }
     }
 
-    // self
-    //   .keymapping
-    //   .iter()
-    //   .map(|(key, note)| (HIDCodes::from_u8(*key).unwrap(), *note))
-    //   .collect();
-
     mapping
   }
+
+  // Mapping for a single channel generated from the layout, leaving out the keys which are used as modifiers
+  pub fn generate_keymapping(
+    &self,
+    layout: &LayoutConfig,
+    channel: Channel,
+  ) -> Result<HashMap<Channel, Vec<KeyMapping>>> {
+    let modifier_keys: Vec<u8> = self
+      .get_modifiers()
+      .iter()
+      .map(|modifier| modifier.key)
+      .collect();
+    let mappings = layout
+      .generate()?
+      .into_iter()
+      .map(|(key, note)| (key as u8, note))
+      .filter(|(key, _)| !modifier_keys.contains(key))
+      .map(|(key, note)| KeyMapping::Note(key, note))
+      .collect();
+    Ok(vec![(channel, mappings)].into_iter().collect())
+  }
+
+  pub fn get_modifiers(&self) -> Vec<ModifierConfig> {
+    self.modifiers.clone().unwrap_or_else(|| {
+      vec![ModifierConfig::new(
+        HIDCodes::LeftShift as u8,
+        self.shift_amount,
+      )]
+    })
+  }
+
+  pub fn get_key_configs(&self) -> HashMap<HIDCodes, KeyConfig> {
+    self
+      .key_configs
+      .iter()
+      .filter_map(|(key, config)| HIDCodes::from_u8(*key).map(|hid_key| (hid_key, config.clone())))
+      .collect()
+  }
 }
 
 impl Default for AppSettings {
@@ -119,23 +263,33 @@ impl Default for AppSettings {
       keymapping: [(
         0,
         vec![
-          (HIDCodes::A as u8, 57),
-          (HIDCodes::W as u8, 58),
-          (HIDCodes::S as u8, 59),
-          (HIDCodes::D as u8, 60),
-          (HIDCodes::R as u8, 61),
-          (HIDCodes::F as u8, 62),
-          (HIDCodes::T as u8, 63),
-          (HIDCodes::G as u8, 64),
-          (HIDCodes::H as u8, 65),
-          (HIDCodes::U as u8, 66),
+          KeyMapping::Note(HIDCodes::A as u8, 57),
+          KeyMapping::Note(HIDCodes::W as u8, 58),
+          KeyMapping::Note(HIDCodes::S as u8, 59),
+          KeyMapping::Note(HIDCodes::D as u8, 60),
+          KeyMapping::Note(HIDCodes::R as u8, 61),
+          KeyMapping::Note(HIDCodes::F as u8, 62),
+          KeyMapping::Note(HIDCodes::T as u8, 63),
+          KeyMapping::Note(HIDCodes::G as u8, 64),
+          KeyMapping::Note(HIDCodes::H as u8, 65),
+          KeyMapping::Note(HIDCodes::U as u8, 66),
         ],
       )]
       .iter()
       .cloned()
       .collect(),
       shift_amount: default_shift_amount(),
+      modifiers: None,
       note_config: Default::default(),
+      key_configs: HashMap::new(),
+      devices: HashMap::new(),
+      outputs: None,
+      preferred_port: None,
+      input: None,
+      mpe: Default::default(),
+      arp: Default::default(),
+      software_sustain: false,
+      scale: Default::default(),
     }
   }
 }
@@ -6,7 +6,80 @@ import { listen } from "@tauri-apps/api/event";
 import { SettingsDispatch } from "./settings-context";
 import { ServiceStateAction, ServiceStateDispatch } from "./state-context";
 
-type PortOption = [number, string, boolean];
+export type OutputRouting =
+  | { type: "all" }
+  | { type: "channels"; channels: number[] };
+
+export type PortState =
+  | { type: "available" }
+  | { type: "connected"; routing: OutputRouting };
+
+export type PortOption = [number, string, PortState];
+
+export interface MpeConfig {
+  enabled: boolean;
+  zone: "lower" | "upper";
+  member_channels: number;
+  timbre: boolean;
+}
+
+export type ArpMode = "up" | "down" | "up_down" | "random" | "as_played";
+
+export interface ArpConfig {
+  enabled: boolean;
+  mode: ArpMode;
+  rate: number;
+  octaves: number;
+}
+
+export type Scale =
+  | {
+      type:
+        | "major"
+        | "minor"
+        | "harmonic_minor"
+        | "melodic_minor"
+        | "dorian"
+        | "phrygian"
+        | "lydian"
+        | "mixolydian"
+        | "locrian"
+        | "major_pentatonic"
+        | "minor_pentatonic"
+        | "blues"
+        | "chromatic";
+    }
+  | { type: "custom"; intervals: number[] };
+
+export interface ScaleConfig {
+  root: number;
+  scale: Scale;
+  transpose_mode: "semitones" | "scale_steps";
+}
+
+export type Layout =
+  | "wicki_hayden"
+  | "janko"
+  | "harmonic_table"
+  | "fourths"
+  | "piano";
+
+export interface LayoutConfig {
+  layout: Layout;
+  origin: HIDCodes;
+  base_note: number;
+}
+
+export interface InputConfig {
+  port: string;
+  channels?: number[];
+  remap: { [channel: string]: number };
+}
+
+export interface OutputConfig {
+  port: string;
+  routing: OutputRouting;
+}
 
 export const MIDI_NOTE_MIN = 21;
 export const MIDI_NOTE_MAX = 108;
@@ -31,23 +104,167 @@ export interface DeviceInfo {
   manufacturer_name: String;
   /// Device name
   device_name: String;
-  /// Unique device ID
-  device_id: number;
+  /// Unique device ID, as a string since it can be larger than a number can hold
+  device_id: string;
   /// Hardware type of the Device
   device_type: DeviceType;
 }
 
 export type DeviceList = DeviceInfo[];
 
+export type Curve =
+  | { type: "linear" }
+  | { type: "exponential"; exponent: number }
+  | { type: "logarithmic"; exponent: number }
+  | { type: "s_curve"; steepness: number }
+  | { type: "fixed"; value: number }
+  | { type: "table"; points: number[] };
+
+export interface VelocityCurve {
+  curve: Curve;
+  min: number;
+  max: number;
+}
+
+export type PressureMode =
+  | { type: "off" }
+  | { type: "poly_aftertouch" }
+  | { type: "channel_pressure" }
+  | { type: "control_change"; control: number };
+
+export type TransposePolicy = "latch" | "retrigger";
+
+export interface RapidTrigger {
+  enabled: boolean;
+  release_distance: number;
+  press_distance: number;
+}
+
 export interface NoteConfig {
   threshold: number;
   velocity_scale: number;
+  velocity_curve: VelocityCurve;
+  release_velocity_scale: number;
+  release_velocity_curve: VelocityCurve;
+  pressure_mode: PressureMode;
+  pressure_from_threshold: boolean;
+  transpose_policy: TransposePolicy;
+  hysteresis: number;
+  noise_floor: number;
+  rapid_trigger: RapidTrigger;
+}
+
+export interface NoteConfigOverride {
+  threshold?: number;
+  velocity_scale?: number;
+  velocity_curve?: VelocityCurve;
+  release_velocity_scale?: number;
+  release_velocity_curve?: VelocityCurve;
+  pressure_mode?: PressureMode;
+  pressure_from_threshold?: boolean;
+  transpose_policy?: TransposePolicy;
+  hysteresis?: number;
+  noise_floor?: number;
+  rapid_trigger?: RapidTrigger;
+}
+
+export interface KeyConfig extends NoteConfigOverride {
+  channels?: { [channel: string]: NoteConfigOverride };
+}
+
+export interface ControlConfig {
+  control: number;
+  deadzone: number;
+  min: number;
+  max: number;
+  invert: boolean;
+  curve: Curve;
+}
+
+export interface PitchBendConfig {
+  direction: "up" | "down";
+  range: number;
+  deadzone: number;
+  curve: Curve;
+}
+
+export type ChordQuality =
+  | "major"
+  | "minor"
+  | "diminished"
+  | "augmented"
+  | "sus2"
+  | "sus4"
+  | "power"
+  | "dominant7"
+  | "major7"
+  | "minor7"
+  | "half_diminished7"
+  | "diminished7"
+  | "major6"
+  | "minor6"
+  | "add9";
+
+export interface ChordConfig {
+  root: number;
+  quality: ChordQuality;
+  inversion: number;
+  voicing: "close" | "open";
+}
+
+export interface SustainConfig {
+  threshold: number;
+  half_pedal: boolean;
+  deadzone: number;
+  curve: Curve;
+}
+
+export type Mapping =
+  | { type: "note"; note: number }
+  | { type: "degree"; degree: number }
+  | ({ type: "chord" } & ChordConfig)
+  | ({ type: "control" } & ControlConfig)
+  | ({ type: "pitch_bend" } & PitchBendConfig)
+  | ({ type: "sustain" } & SustainConfig);
+
+export type NoteMapping = [HIDCodes, number];
+
+export type KeyMapping = NoteMapping | ({ key: HIDCodes } & Mapping);
+
+export function isNoteMapping(mapping: KeyMapping): mapping is NoteMapping {
+  return Array.isArray(mapping);
+}
+
+export function mappingKey(mapping: KeyMapping): HIDCodes {
+  return isNoteMapping(mapping) ? mapping[0] : mapping.key;
+}
+
+export interface ModifierConfig {
+  key: HIDCodes;
+  shift: number;
+  actuation_point: number;
+  mode: "momentary" | "toggle";
+}
+
+export interface DeviceSettings {
+  keymapping?: { [channel: string]: KeyMapping[] };
+  channel?: number;
+  transpose: number;
 }
 
 export interface AppSettings {
-  keymapping: { [channel: string]: [HIDCodes, number][] };
+  keymapping: { [channel: string]: KeyMapping[] };
   shift_amount: number;
+  modifiers?: ModifierConfig[];
   note_config: NoteConfig;
+  key_configs: { [key: string]: KeyConfig };
+  devices: { [device_id: string]: DeviceSettings };
+  outputs?: OutputConfig[];
+  mpe: MpeConfig;
+  arp: ArpConfig;
+  software_sustain: boolean;
+  scale: ScaleConfig;
+  input?: InputConfig;
 }
 
 export interface MidiEntry {
@@ -137,12 +354,60 @@ export class Backend extends EventEmitter {
     return callAppFunction("get_connected_devices");
   }
 
-  async selectPort(option: number): Promise<PortOptions> {
-    return callAppFunction<PortOptions>("select_port", {
-      option: option,
+  async getInputOptions(): Promise<string[]> {
+    return callAppFunction("get_input_options");
+  }
+
+  async getMidiState(): Promise<MidiUpdate> {
+    return callAppFunction("get_midi_state");
+  }
+
+  async generateKeymapping(
+    layout: LayoutConfig,
+    channel: number
+  ): Promise<{ [channel: string]: KeyMapping[] }> {
+    return callAppFunction("generate_keymapping", { layout, channel });
+  }
+
+  async addOutput(config: OutputConfig): Promise<PortOptions> {
+    return callAppFunction<PortOptions>("add_output", {
+      config: config,
     });
   }
 
+  async removeOutput(port: string): Promise<PortOptions> {
+    return callAppFunction<PortOptions>("remove_output", {
+      port: port,
+    });
+  }
+
+  async getKeyConfig(key: HIDCodes): Promise<KeyConfig> {
+    return callAppFunction("get_key_config", { key });
+  }
+
+  async setKeyConfig(key: HIDCodes, config: KeyConfig): Promise<void> {
+    const settings = await callAppFunction<AppSettings>("set_key_config", {
+      key,
+      config,
+    });
+    this.settingsDispatcher?.({ type: "change", settings });
+  }
+
+  async getDeviceSettings(deviceId: string): Promise<DeviceSettings> {
+    return callAppFunction("get_device_settings", { device: deviceId });
+  }
+
+  async setDeviceSettings(
+    deviceId: string,
+    settings: DeviceSettings
+  ): Promise<void> {
+    const appSettings = await callAppFunction<AppSettings>(
+      "set_device_settings",
+      { device: deviceId, settings }
+    );
+    this.settingsDispatcher?.({ type: "change", settings: appSettings });
+  }
+
   async requestConfig(): Promise<AppSettings> {
     return callAppFunction("get_config");
   }
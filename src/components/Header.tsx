@@ -1,14 +1,21 @@
 import { MoonIcon, SunIcon } from "@chakra-ui/icons";
 import {
+  Button,
   HStack,
   IconButton,
-  Select,
+  Menu,
+  MenuButton,
+  MenuItemOption,
+  MenuList,
+  MenuOptionGroup,
   Text,
   useColorMode,
 } from "@chakra-ui/react";
 import React from "react";
+import { OutputRouting } from "../backend";
 import {
-  selectPort,
+  setOutputEnabled,
+  setOutputRouting,
   useDevices,
   usePortOptions,
   useServiceDispatch,
@@ -19,9 +26,35 @@ export function Header() {
   const portOptions = usePortOptions();
   const serviceDispatch = useServiceDispatch();
 
-  function onPortSelectionChanged(choice: number) {
-    console.log("Selected " + choice);
-    selectPort(serviceDispatch, choice);
+  const connectedPorts = portOptions
+    .filter((item) => item[2].type === "connected")
+    .map((item) => item[1]);
+
+  function onPortSelectionChanged(selected: string[]) {
+    portOptions.forEach(([, name]) => {
+      const enabled = selected.includes(name);
+      if (enabled !== connectedPorts.includes(name)) {
+        setOutputEnabled(serviceDispatch, name, enabled);
+      }
+    });
+  }
+
+  function routingLabel(routing: OutputRouting): string {
+    return routing.type === "channels"
+      ? ` (channels ${routing.channels.join(", ")})`
+      : "";
+  }
+
+  function onRoutingChanged(port: string, selected: string[]) {
+    // Not picking any channels sends them all
+    const channels = selected
+      .map((value) => parseInt(value))
+      .sort((a, b) => a - b);
+    setOutputRouting(
+      serviceDispatch,
+      port,
+      channels.length > 0 ? { type: "channels", channels } : { type: "all" }
+    );
   }
   const { colorMode, toggleColorMode } = useColorMode();
 
@@ -39,20 +72,60 @@ export function Header() {
           onClick={toggleColorMode}
           icon={colorMode === "light" ? <MoonIcon /> : <SunIcon />}
         />
-        <Text minW="max-content">Output Port:</Text>
+        <Text minW="max-content">Outputs:</Text>
         {(portOptions?.length ?? 0) > 0 && (
-          <Select
-            value={portOptions.findIndex((item) => item[2])}
-            onChange={(event) => {
-              onPortSelectionChanged(parseInt(event.target.value));
-            }}
-          >
-            {portOptions.map((item) => (
-              <option key={item[0]} value={item[0]}>
-                {item[1]}
-              </option>
-            ))}
-          </Select>
+          <Menu closeOnSelect={false}>
+            <MenuButton as={Button} variant="outline" minW="max-content">
+              {connectedPorts.length > 0 ? connectedPorts.join(", ") : "None"}
+            </MenuButton>
+            <MenuList>
+              <MenuOptionGroup
+                type="checkbox"
+                value={connectedPorts}
+                onChange={(value) => {
+                  onPortSelectionChanged(value as string[]);
+                }}
+              >
+                {portOptions.map(([index, name, state]) => (
+                  <MenuItemOption key={index} value={name}>
+                    {name}
+                    {state.type === "connected" && routingLabel(state.routing)}
+                  </MenuItemOption>
+                ))}
+              </MenuOptionGroup>
+            </MenuList>
+          </Menu>
+        )}
+        {portOptions.map(
+          ([index, name, state]) =>
+            state.type === "connected" && (
+              <Menu key={index} closeOnSelect={false}>
+                <MenuButton as={Button} variant="ghost" minW="max-content">
+                  {name}
+                  {routingLabel(state.routing) || " (all channels)"}
+                </MenuButton>
+                <MenuList>
+                  <MenuOptionGroup
+                    title="Channels"
+                    type="checkbox"
+                    value={
+                      state.routing.type === "channels"
+                        ? state.routing.channels.map(String)
+                        : []
+                    }
+                    onChange={(value) => {
+                      onRoutingChanged(name, value as string[]);
+                    }}
+                  >
+                    {[...Array(16).keys()].map((i) => (
+                      <MenuItemOption key={i} value={i.toString()}>
+                        {i}
+                      </MenuItemOption>
+                    ))}
+                  </MenuOptionGroup>
+                </MenuList>
+              </Menu>
+            )
         )}
       </HStack>
     </HStack>
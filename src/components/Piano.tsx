@@ -1,7 +1,7 @@
 import React, { useEffect, useState } from "react";
 import { PianoDisplay, MidiDataEntry } from "./PianoDisplay";
 import { HIDCodes } from "../HidCodes";
-import { MidiUpdate } from "../backend";
+import { isNoteMapping, MidiUpdate } from "../backend";
 import { useSettings } from "../settings-context";
 import { useServiceState } from "../state-context";
 import { Box, HStack, Select, Text } from "@chakra-ui/react";
@@ -108,7 +108,10 @@ export function PianoBody() {
 
   let pianoData: MidiDataEntry[] = [];
 
-  const channelMapping = appSettings.keymapping[selectedChannel] || [];
+  const fullMapping = appSettings.keymapping[selectedChannel] || [];
+  // The piano only deals with notes, any other kind of mapping is kept as is
+  const channelMapping = fullMapping.filter(isNoteMapping);
+  const otherMapping = fullMapping.filter((mapping) => !isNoteMapping(mapping));
 
   channelMapping.forEach(([key, note_id]) => {
     const entry = serviceState.midiState.data[key];
@@ -153,7 +156,7 @@ export function PianoBody() {
         changeMapping={(mapping) =>
           appSettingsDispatch({
             type: "CHANGE_MAPPING",
-            mapping,
+            mapping: [...mapping, ...otherMapping],
             channel: selectedChannel,
           })
         }
@@ -1,19 +1,71 @@
-import React from "react";
-import { MIDI_NOTE_MAX } from "../backend";
+import React, { useEffect, useState } from "react";
+import {
+  ArpMode,
+  backend,
+  DeviceSettings,
+  isNoteMapping,
+  KeyMapping,
+  Layout,
+  mappingKey,
+  MIDI_NOTE_MAX,
+  MIDI_NOTE_MIN,
+  Scale,
+  ScaleConfig,
+} from "../backend";
 import { useSettings } from "../settings-context";
+import { useDevices } from "../state-context";
+import { HIDCodes } from "../HidCodes";
 import {
+  Button,
   NumberInput,
   NumberInputField,
   NumberInputStepper,
   NumberIncrementStepper,
   NumberDecrementStepper,
+  Select,
+  Switch,
   Text,
   HStack,
   VStack,
 } from "@chakra-ui/react";
 
+// Replaces the note mappings of the generated channels, the other channels and any other kind of mapping are kept as is
+function mergeKeymapping(
+  current: { [channel: string]: KeyMapping[] },
+  generated: { [channel: string]: KeyMapping[] }
+): { [channel: string]: KeyMapping[] } {
+  const merged = { ...current };
+  Object.entries(generated).forEach(([channel, mappings]) => {
+    const otherMapping = (current[channel] ?? []).filter(
+      (mapping) => !isNoteMapping(mapping)
+    );
+    const usedKeys = otherMapping.map(mappingKey);
+    merged[channel] = [
+      ...otherMapping,
+      ...mappings.filter((mapping) => !usedKeys.includes(mappingKey(mapping))),
+    ];
+  });
+  return merged;
+}
+
 export function Settings() {
   const [appSettings, appSettingsDispatch] = useSettings();
+  const [inputOptions, setInputOptions] = useState<string[]>([]);
+  const [layout, setLayout] = useState<Layout>("wicki_hayden");
+  const devices = useDevices();
+
+  function updateDevice(deviceId: string, change: Partial<DeviceSettings>) {
+    const current = appSettings.devices[deviceId] ?? { transpose: 0 };
+    backend
+      .setDeviceSettings(deviceId, { ...current, ...change })
+      .catch((err) => {
+        console.error("Error while changing device settings ", err.message);
+      });
+  }
+
+  useEffect(() => {
+    backend.getInputOptions().then(setInputOptions);
+  }, []);
 
   return (
     <HStack flexWrap="wrap" justifyContent="space-evenly">
@@ -40,6 +92,113 @@ export function Settings() {
           </NumberInputStepper>
         </NumberInput>
       </VStack>
+      <VStack>
+        <Text>Scale</Text>
+
+        <HStack>
+          <NumberInput
+            value={appSettings.scale.root}
+            min={MIDI_NOTE_MIN}
+            max={MIDI_NOTE_MAX}
+            onChange={(_, value) => {
+              if (!isNaN(value)) {
+                appSettingsDispatch({
+                  type: "SCALE_CHANGED",
+                  value: { root: value },
+                });
+              }
+            }}
+          >
+            <NumberInputField />
+            <NumberInputStepper>
+              <NumberIncrementStepper />
+              <NumberDecrementStepper />
+            </NumberInputStepper>
+          </NumberInput>
+          <Select
+            value={appSettings.scale.scale.type}
+            onChange={(event) => {
+              const type = event.target.value as Scale["type"];
+              appSettingsDispatch({
+                type: "SCALE_CHANGED",
+                value: {
+                  scale:
+                    type === "custom"
+                      ? { type, intervals: [0, 2, 4, 5, 7, 9, 11] }
+                      : ({ type } as Scale),
+                },
+              });
+            }}
+          >
+            <option value="major">Major</option>
+            <option value="minor">Minor</option>
+            <option value="harmonic_minor">Harmonic Minor</option>
+            <option value="melodic_minor">Melodic Minor</option>
+            <option value="dorian">Dorian</option>
+            <option value="phrygian">Phrygian</option>
+            <option value="lydian">Lydian</option>
+            <option value="mixolydian">Mixolydian</option>
+            <option value="locrian">Locrian</option>
+            <option value="major_pentatonic">Major Pentatonic</option>
+            <option value="minor_pentatonic">Minor Pentatonic</option>
+            <option value="blues">Blues</option>
+            <option value="chromatic">Chromatic</option>
+            <option value="custom">Custom</option>
+          </Select>
+        </HStack>
+        <HStack>
+          <Text>Shift by</Text>
+          <Select
+            value={appSettings.scale.transpose_mode}
+            onChange={(event) => {
+              appSettingsDispatch({
+                type: "SCALE_CHANGED",
+                value: {
+                  transpose_mode: event.target
+                    .value as ScaleConfig["transpose_mode"],
+                },
+              });
+            }}
+          >
+            <option value="semitones">Semitones</option>
+            <option value="scale_steps">Scale Steps</option>
+          </Select>
+        </HStack>
+      </VStack>
+      <VStack>
+        <Text>Keyboard Layout</Text>
+
+        <Select
+          value={layout}
+          onChange={(event) => setLayout(event.target.value as Layout)}
+        >
+          <option value="wicki_hayden">Wicki-Hayden</option>
+          <option value="janko">Janko</option>
+          <option value="harmonic_table">Harmonic Table</option>
+          <option value="fourths">Fourths</option>
+          <option value="piano">Piano</option>
+        </Select>
+        <Button
+          onClick={() => {
+            backend
+              .generateKeymapping(
+                // The piano layout has its white keys on the bottom row, the others are centred on the home row
+                layout === "piano"
+                  ? { layout, origin: HIDCodes.Z, base_note: 48 }
+                  : { layout, origin: HIDCodes.G, base_note: 60 },
+                0
+              )
+              .then((generated) =>
+                appSettingsDispatch({
+                  type: "KEYMAPPING_CHANGED",
+                  value: mergeKeymapping(appSettings.keymapping, generated),
+                })
+              );
+          }}
+        >
+          Generate
+        </Button>
+      </VStack>
       <VStack>
         <Text>Note Trigger Threshold</Text>
 
@@ -89,6 +248,194 @@ export function Settings() {
           </NumberInputStepper>
         </NumberInput>
       </VStack>
+      <VStack>
+        <Text>MPE</Text>
+
+        <Switch
+          isChecked={appSettings.mpe.enabled}
+          onChange={(event) => {
+            appSettingsDispatch({
+              type: "MPE_CHANGED",
+              value: { enabled: event.target.checked },
+            });
+          }}
+        />
+        <HStack>
+          <Text>Timbre (CC74)</Text>
+          <Switch
+            isChecked={appSettings.mpe.timbre}
+            isDisabled={!appSettings.mpe.enabled}
+            onChange={(event) => {
+              appSettingsDispatch({
+                type: "MPE_CHANGED",
+                value: { timbre: event.target.checked },
+              });
+            }}
+          />
+        </HStack>
+      </VStack>
+      <VStack>
+        <Text>Software Sustain</Text>
+
+        <Switch
+          isChecked={appSettings.software_sustain}
+          onChange={(event) => {
+            appSettingsDispatch({
+              type: "SOFTWARE_SUSTAIN_CHANGED",
+              value: event.target.checked,
+            });
+          }}
+        />
+      </VStack>
+      <VStack>
+        <Text>Arpeggiator</Text>
+
+        <Switch
+          isChecked={appSettings.arp.enabled}
+          onChange={(event) => {
+            appSettingsDispatch({
+              type: "ARP_CHANGED",
+              value: { enabled: event.target.checked },
+            });
+          }}
+        />
+        <Select
+          value={appSettings.arp.mode}
+          onChange={(event) => {
+            appSettingsDispatch({
+              type: "ARP_CHANGED",
+              value: { mode: event.target.value as ArpMode },
+            });
+          }}
+        >
+          <option value="up">Up</option>
+          <option value="down">Down</option>
+          <option value="up_down">Up/Down</option>
+          <option value="random">Random</option>
+          <option value="as_played">As Played</option>
+        </Select>
+        <HStack>
+          <Text>Steps/s</Text>
+          <NumberInput
+            type="number"
+            value={appSettings.arp.rate}
+            onChange={(_, value) => {
+              if (!isNaN(value)) {
+                appSettingsDispatch({
+                  type: "ARP_CHANGED",
+                  value: { rate: value },
+                });
+              }
+            }}
+            min={0.5}
+            max={32}
+            step={0.5}
+          >
+            <NumberInputField />
+            <NumberInputStepper>
+              <NumberIncrementStepper />
+              <NumberDecrementStepper />
+            </NumberInputStepper>
+          </NumberInput>
+        </HStack>
+        <HStack>
+          <Text>Octaves</Text>
+          <NumberInput
+            type="number"
+            value={appSettings.arp.octaves}
+            onChange={(_, value) => {
+              if (!isNaN(value)) {
+                appSettingsDispatch({
+                  type: "ARP_CHANGED",
+                  value: { octaves: value },
+                });
+              }
+            }}
+            min={1}
+            max={4}
+            step={1}
+          >
+            <NumberInputField />
+            <NumberInputStepper>
+              <NumberIncrementStepper />
+              <NumberDecrementStepper />
+            </NumberInputStepper>
+          </NumberInput>
+        </HStack>
+      </VStack>
+      {devices.map((device) => {
+        const deviceSettings = appSettings.devices[device.device_id];
+        return (
+          <VStack key={device.device_id}>
+            <Text>{device.device_name}</Text>
+
+            <HStack>
+              <Text>Channel</Text>
+              <Select
+                value={deviceSettings?.channel ?? ""}
+                onChange={(event) => {
+                  const value = event.target.value;
+                  updateDevice(device.device_id, {
+                    channel: value === "" ? undefined : parseInt(value),
+                  });
+                }}
+              >
+                <option value="">As Mapped</option>
+                {[...Array(16).keys()].map((i) => (
+                  <option key={i} value={i}>
+                    {i}
+                  </option>
+                ))}
+              </Select>
+            </HStack>
+            <HStack>
+              <Text>Transpose</Text>
+              <NumberInput
+                value={deviceSettings?.transpose ?? 0}
+                min={-MIDI_NOTE_MAX}
+                max={MIDI_NOTE_MAX}
+                onChange={(_, value) => {
+                  if (!isNaN(value)) {
+                    updateDevice(device.device_id, { transpose: value });
+                  }
+                }}
+              >
+                <NumberInputField />
+                <NumberInputStepper>
+                  <NumberIncrementStepper />
+                  <NumberDecrementStepper />
+                </NumberInputStepper>
+              </NumberInput>
+            </HStack>
+          </VStack>
+        );
+      })}
+      <VStack>
+        <Text>MIDI Input</Text>
+
+        <Select
+          value={appSettings.input?.port ?? ""}
+          onFocus={() => {
+            backend.getInputOptions().then(setInputOptions);
+          }}
+          onChange={(event) => {
+            const port = event.target.value;
+            appSettingsDispatch({
+              type: "INPUT_CHANGED",
+              value: port
+                ? { ...(appSettings.input ?? { remap: {} }), port }
+                : undefined,
+            });
+          }}
+        >
+          <option value="">None</option>
+          {inputOptions.map((port) => (
+            <option key={port} value={port}>
+              {port}
+            </option>
+          ))}
+        </Select>
+      </VStack>
     </HStack>
   );
 }
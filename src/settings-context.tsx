@@ -1,15 +1,28 @@
 import * as React from "react";
 import { useEffect } from "react";
-import { AppSettings, backend } from "./backend";
-import { HIDCodes } from "./HidCodes";
+import {
+  AppSettings,
+  ArpConfig,
+  backend,
+  InputConfig,
+  KeyMapping,
+  MpeConfig,
+  ScaleConfig,
+} from "./backend";
 type InitAction<S> = { type: "INIT"; value: S };
 
 type SettingsAction =
   | { type: "change"; settings: AppSettings }
-  | { type: "CHANGE_MAPPING"; mapping: [HIDCodes, number][]; channel: number }
+  | { type: "CHANGE_MAPPING"; mapping: KeyMapping[]; channel: number }
+  | { type: "KEYMAPPING_CHANGED"; value: { [channel: string]: KeyMapping[] } }
   | { type: "NOTE_SHIFT_CHANGED"; value: number }
   | { type: "THRESHOLD_CHANGED"; value: number }
   | { type: "VELOCITY_SCALE_CHANGED"; value: number }
+  | { type: "INPUT_CHANGED"; value?: InputConfig }
+  | { type: "MPE_CHANGED"; value: Partial<MpeConfig> }
+  | { type: "ARP_CHANGED"; value: Partial<ArpConfig> }
+  | { type: "SOFTWARE_SUSTAIN_CHANGED"; value: boolean }
+  | { type: "SCALE_CHANGED"; value: Partial<ScaleConfig> }
   | InitAction<AppSettings>;
 export type SettingsDispatch = (action: SettingsAction) => void;
 type SettingsState = AppSettings;
@@ -38,6 +51,8 @@ function settingsReducer(
           [action.channel]: action.mapping,
         },
       };
+    case "KEYMAPPING_CHANGED":
+      return { ...state, keymapping: action.value };
     case "THRESHOLD_CHANGED":
       return {
         ...state,
@@ -51,6 +66,16 @@ function settingsReducer(
         ...state,
         note_config: { ...state.note_config, velocity_scale: action.value },
       };
+    case "INPUT_CHANGED":
+      return { ...state, input: action.value };
+    case "MPE_CHANGED":
+      return { ...state, mpe: { ...state.mpe, ...action.value } };
+    case "ARP_CHANGED":
+      return { ...state, arp: { ...state.arp, ...action.value } };
+    case "SOFTWARE_SUSTAIN_CHANGED":
+      return { ...state, software_sustain: action.value };
+    case "SCALE_CHANGED":
+      return { ...state, scale: { ...state.scale, ...action.value } };
     default: {
       //@ts-ignore
       console.error(`Unhandled action type: ${action.type}`);
@@ -1,6 +1,13 @@
 import * as React from "react";
 import { useEffect } from "react";
-import { backend, DeviceList, MidiUpdate, PortOptions } from "./backend";
+import {
+  backend,
+  DeviceInfo,
+  DeviceList,
+  MidiUpdate,
+  OutputRouting,
+  PortOptions,
+} from "./backend";
 import {
   createContext,
   useContextSelector,
@@ -12,7 +19,9 @@ export type ServiceStateAction =
   | { type: "MIDI_UPDATE"; value: MidiUpdate }
   | { type: "PORT_OPTIONS"; value: PortOptions }
   | { type: "FOUND_DEVICES"; value: DeviceList }
-  | { type: "NO_DEVICES" };
+  | { type: "NO_DEVICES" }
+  | { type: "DEVICE_CONNECTED"; value: DeviceInfo }
+  | { type: "DEVICE_DISCONNECTED"; value: DeviceInfo };
 export type ServiceStateDispatch = (action: ServiceStateAction) => void;
 export interface ServiceStateState {
   midiState: MidiUpdate;
@@ -45,6 +54,23 @@ function serviceStateReducer(
       return { ...state, connectedDevices: [] };
     case "FOUND_DEVICES":
       return { ...state, connectedDevices: action.value };
+    case "DEVICE_CONNECTED":
+      return {
+        ...state,
+        connectedDevices: [
+          ...state.connectedDevices.filter(
+            (device) => device.device_id !== action.value.device_id
+          ),
+          action.value,
+        ],
+      };
+    case "DEVICE_DISCONNECTED":
+      return {
+        ...state,
+        connectedDevices: state.connectedDevices.filter(
+          (device) => device.device_id !== action.value.device_id
+        ),
+      };
     default: {
       //@ts-ignore
       console.error(`Unhandled action type: ${action.type}`);
@@ -114,24 +140,49 @@ function useDevices(): DeviceList {
   return useServiceSelector((state) => state.connectedDevices);
 }
 
-function selectPort(dispatch: ServiceStateDispatch, option: number) {
-  backend
-    .selectPort(option)
+function updateOutputs(
+  dispatch: ServiceStateDispatch,
+  request: Promise<PortOptions>
+) {
+  request
     .then((ports) => {
       dispatch({ type: "PORT_OPTIONS", value: ports });
     })
     .catch((err) => {
-      console.error("Error while selecting ports ", err.message);
+      console.error("Error while changing outputs ", err.message);
     });
 }
 
+function setOutputEnabled(
+  dispatch: ServiceStateDispatch,
+  port: string,
+  enabled: boolean
+) {
+  updateOutputs(
+    dispatch,
+    enabled
+      ? backend.addOutput({ port, routing: { type: "all" } })
+      : backend.removeOutput(port)
+  );
+}
+
+// Adding a port which is already an output only changes its routing
+function setOutputRouting(
+  dispatch: ServiceStateDispatch,
+  port: string,
+  routing: OutputRouting
+) {
+  updateOutputs(dispatch, backend.addOutput({ port, routing }));
+}
+
 export {
   ServiceStateProvider,
   useServiceState,
   useServiceDispatch,
   useService,
   useServiceSelector,
-  selectPort,
+  setOutputEnabled,
+  setOutputRouting,
   usePortOptions,
   useMidiState,
   useDevices,
@@ -0,0 +1,297 @@
+use crate::output::Outputs;
+use crate::{Channel, NoteID, NoteSink, DEFAULT_RELEASE_VELOCITY, MIDI_NOTE_MAX};
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+// Shortest gate, so a key that's barely pressed still makes a sound
+const MIN_GATE: f32 = 0.05;
+const MIN_RATE: f32 = 0.1;
+
+fn default_rate() -> f32 {
+    8.0
+}
+
+fn default_octaves() -> u8 {
+    1
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ArpMode {
+    #[default]
+    Up,
+    Down,
+    UpDown,
+    Random,
+    // In the order the keys were pressed
+    AsPlayed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ArpConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub mode: ArpMode,
+    // Steps per second
+    #[serde(default = "default_rate")]
+    pub rate: f32,
+    // Number of octaves the held notes are repeated over
+    #[serde(default = "default_octaves")]
+    pub octaves: u8,
+}
+
+impl Default for ArpConfig {
+    fn default() -> Self {
+        ArpConfig {
+            enabled: false,
+            mode: Default::default(),
+            rate: default_rate(),
+            octaves: default_octaves(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct HeldNote {
+    note_id: NoteID,
+    channel: Channel,
+    velocity: f32,
+    // Depth of the key holding the note, which sets the gate length
+    depth: f32,
+}
+
+// Cycles through the held notes, playing one per step
+#[derive(Debug, Clone)]
+pub struct Arpeggiator {
+    config: ArpConfig,
+    // In the order they were pressed
+    held: Vec<HeldNote>,
+    position: usize,
+    next_step: Option<Instant>,
+    // The note that's currently on and when its gate ends
+    sounding: Option<(NoteID, Channel, Instant)>,
+    random_state: u32,
+}
+
+impl Arpeggiator {
+    pub fn new(config: ArpConfig) -> Self {
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |time| time.subsec_nanos());
+        Arpeggiator {
+            config,
+            held: vec![],
+            position: 0,
+            next_step: None,
+            sounding: None,
+            // Xorshift gets stuck on 0
+            random_state: seed | 1,
+        }
+    }
+
+    pub fn config(&self) -> &ArpConfig {
+        &self.config
+    }
+
+    // Keeps the held notes, so the settings can be changed while playing
+    pub fn set_config(&mut self, config: ArpConfig) {
+        self.config = config;
+    }
+
+    pub fn hold(&mut self, note_id: NoteID, velocity: f32, channel: Channel) {
+        self.held
+            .retain(|held| !(held.note_id == note_id && held.channel == channel));
+        self.held.push(HeldNote {
+            note_id,
+            channel,
+            velocity,
+            depth: 1.0,
+        });
+    }
+
+    pub fn release(
+        &mut self,
+        note_id: NoteID,
+        channel: Channel,
+        sink: &mut dyn NoteSink,
+    ) -> Result<()> {
+        self.held
+            .retain(|held| !(held.note_id == note_id && held.channel == channel));
+        if self.held.is_empty() {
+            self.stop(sink)?;
+            self.next_step = None;
+            self.position = 0;
+        }
+        Ok(())
+    }
+
+    pub fn set_depth(&mut self, note_id: NoteID, channel: Channel, depth: f32) {
+        for held in self.held.iter_mut() {
+            if held.note_id == note_id && held.channel == channel {
+                held.depth = depth;
+            }
+        }
+    }
+
+    // The step note that's currently on
+    pub fn sounding(&self) -> Option<(NoteID, Channel)> {
+        self.sounding
+            .map(|(note_id, channel, _)| (note_id, channel))
+    }
+
+    fn stop(&mut self, sink: &mut dyn NoteSink) -> Result<()> {
+        if let Some((note_id, channel, _)) = self.sounding.take() {
+            sink.note_off(note_id, DEFAULT_RELEASE_VELOCITY, channel)?;
+        }
+        Ok(())
+    }
+
+    fn random(&mut self, max: usize) -> usize {
+        let mut x = self.random_state;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.random_state = x;
+        x as usize % max
+    }
+
+    // The steps of one cycle as (index into `held`, note to play)
+    fn sequence(&self) -> Vec<(usize, NoteID)> {
+        let mut order: Vec<usize> = (0..self.held.len()).collect();
+        if self.config.mode != ArpMode::AsPlayed {
+            order.sort_by_key(|index| (self.held[*index].note_id, self.held[*index].channel));
+        }
+        let mut steps: Vec<(usize, NoteID)> = (0..self.config.octaves.max(1) as u16)
+            .flat_map(|octave| {
+                order.iter().filter_map(move |index| {
+                    let note = self.held[*index].note_id as u16 + octave * 12;
+                    if note <= MIDI_NOTE_MAX as u16 {
+                        Some((*index, note as NoteID))
+                    } else {
+                        None
+                    }
+                })
+            })
+            .collect();
+        match self.config.mode {
+            ArpMode::Down => steps.reverse(),
+            ArpMode::UpDown if steps.len() > 2 => {
+                // The top and bottom notes aren't repeated when changing direction
+                let down: Vec<(usize, NoteID)> =
+                    steps[1..steps.len() - 1].iter().rev().cloned().collect();
+                steps.extend(down);
+            }
+            _ => {}
+        }
+        steps
+    }
+
+    // Plays whatever is due at `now`, returning when it next needs to be called
+    pub fn tick(&mut self, now: Instant, sink: &mut dyn NoteSink) -> Result<Option<Instant>> {
+        if let Some((_, _, gate_end)) = self.sounding {
+            if now >= gate_end {
+                self.stop(sink)?;
+            }
+        }
+        if self.held.is_empty() {
+            return Ok(None);
+        }
+
+        let step = Duration::from_secs_f32(1.0 / self.config.rate.max(MIN_RATE));
+        if !matches!(self.next_step, Some(next_step) if now < next_step) {
+            self.stop(sink)?;
+            let sequence = self.sequence();
+            if !sequence.is_empty() {
+                let index = match self.config.mode {
+                    ArpMode::Random => self.random(sequence.len()),
+                    _ => self.position % sequence.len(),
+                };
+                let (held_index, note_id) = sequence[index];
+                let held = &self.held[held_index];
+                sink.note_on(note_id, held.velocity, held.channel)?;
+                let gate = held.depth.clamp(MIN_GATE, 1.0);
+                self.sounding = Some((note_id, held.channel, now + step.mul_f32(gate)));
+                self.position = self.position.wrapping_add(1);
+            }
+            // Steps are timed from when the previous one was due so they don't drift, unless we've fallen a whole step behind
+            self.next_step = Some(match self.next_step {
+                Some(next_step) if now < next_step + step => next_step + step,
+                _ => now + step,
+            });
+        }
+
+        let gate_end = self.sounding.map(|(_, _, gate_end)| gate_end);
+        Ok(match (self.next_step, gate_end) {
+            (Some(next_step), Some(gate_end)) => Some(next_step.min(gate_end)),
+            (next_step, gate_end) => next_step.or(gate_end),
+        })
+    }
+}
+
+// Steps the arpeggiator of the outputs on its own thread, so the timing doesn't depend on how often the service gets polled
+pub(crate) struct ArpThread {
+    outputs: Arc<Mutex<Outputs>>,
+    wake: Arc<Condvar>,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl ArpThread {
+    pub fn start(outputs: Arc<Mutex<Outputs>>) -> Self {
+        let wake = outputs.lock().unwrap().arp_wake();
+        let running = Arc::new(AtomicBool::new(true));
+        let (outputs_inner, wake_inner, running_inner) =
+            (outputs.clone(), wake.clone(), running.clone());
+        let handle = thread::spawn(move || {
+            let mut outputs = outputs_inner.lock().unwrap();
+            while running_inner.load(Ordering::SeqCst) {
+                let now = Instant::now();
+                let next = match outputs.arp_tick(now) {
+                    Ok(next) => next,
+                    Err(e) => {
+                        warn!("Failed to play arpeggiator step: {}", e);
+                        None
+                    }
+                };
+                // Sleeps until the next step or gate end, the outputs wake it early when a note gets held
+                outputs = match next {
+                    Some(next) => {
+                        wake_inner
+                            .wait_timeout(outputs, next.saturating_duration_since(now))
+                            .unwrap()
+                            .0
+                    }
+                    None => wake_inner.wait(outputs).unwrap(),
+                };
+            }
+        });
+        ArpThread {
+            outputs,
+            wake,
+            running,
+            handle: Some(handle),
+        }
+    }
+}
+
+impl Drop for ArpThread {
+    fn drop(&mut self) {
+        {
+            // Changed with the lock held, so the thread can't miss the wake up between checking and waiting
+            let _outputs = self.outputs.lock().unwrap();
+            self.running.store(false, Ordering::SeqCst);
+        }
+        self.wake.notify_all();
+        if let Some(handle) = self.handle.take() {
+            if handle.join().is_err() {
+                error!("Arpeggiator thread panicked");
+            }
+        }
+    }
+}
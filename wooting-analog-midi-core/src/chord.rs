@@ -0,0 +1,107 @@
+use crate::NoteID;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordQuality {
+    #[default]
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    // Root and fifth
+    Power,
+    Dominant7,
+    Major7,
+    Minor7,
+    HalfDiminished7,
+    Diminished7,
+    Major6,
+    Minor6,
+    Add9,
+}
+
+impl ChordQuality {
+    // Semitones above the root of each tone, lowest first
+    pub fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Sus2 => &[0, 2, 7],
+            ChordQuality::Sus4 => &[0, 5, 7],
+            ChordQuality::Power => &[0, 7],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+            ChordQuality::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordQuality::Diminished7 => &[0, 3, 6, 9],
+            ChordQuality::Major6 => &[0, 4, 7, 9],
+            ChordQuality::Minor6 => &[0, 3, 7, 9],
+            ChordQuality::Add9 => &[0, 4, 7, 14],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordVoicing {
+    // Tones stacked as closely as possible
+    #[default]
+    Close,
+    // The second lowest tone is moved up an octave to spread the chord out
+    Open,
+}
+
+// Settings for a key which plays several notes at once
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChordConfig {
+    pub root: NoteID,
+    #[serde(default)]
+    pub quality: ChordQuality,
+    // How many of the lowest tones get moved up an octave, 1 is the first inversion
+    #[serde(default)]
+    pub inversion: u8,
+    #[serde(default)]
+    pub voicing: ChordVoicing,
+}
+
+impl ChordConfig {
+    pub fn new(root: NoteID, quality: ChordQuality) -> Self {
+        ChordConfig {
+            root,
+            quality,
+            inversion: 0,
+            voicing: Default::default(),
+        }
+    }
+
+    // The notes of the chord, lowest first. Tones which would end up outside of the MIDI range are left out
+    pub fn notes(&self) -> Vec<NoteID> {
+        let mut tones: Vec<i16> = self
+            .quality
+            .intervals()
+            .iter()
+            .map(|interval| self.root as i16 + *interval as i16)
+            .collect();
+        // Inversions past the last tone carry on into the next octave
+        for _ in 0..self.inversion {
+            let lowest = tones.remove(0);
+            tones.push(lowest + 12);
+            tones.sort_unstable();
+        }
+        if self.voicing == ChordVoicing::Open && tones.len() >= 3 {
+            let second = tones.remove(1);
+            tones.push(second + 12);
+            tones.sort_unstable();
+        }
+        tones
+            .into_iter()
+            .filter(|note| (0..=127).contains(note))
+            .map(|note| note as NoteID)
+            .collect()
+    }
+}
@@ -0,0 +1,230 @@
+use crate::{Channel, Curve, NoteSink};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+fn default_max() -> f32 {
+    1.0
+}
+
+// How far the key has travelled past the deadzone, between 0.0 and 1.0
+fn travel(depth: f32, deadzone: f32) -> f32 {
+    if depth <= deadzone || deadzone >= 1.0 {
+        0.0
+    } else {
+        (depth - deadzone) / (1.0 - deadzone)
+    }
+}
+
+// Settings for a key which drives a Control Change (e.g. CC1 mod wheel, CC11 expression) from its analog depth
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlConfig {
+    pub control: u8,
+    // Depth which has to be passed before the value starts moving away from `min`
+    #[serde(default)]
+    pub deadzone: f32,
+    #[serde(default)]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+    #[serde(default)]
+    pub invert: bool,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl ControlConfig {
+    pub fn new(control: u8) -> Self {
+        ControlConfig {
+            control,
+            deadzone: 0.0,
+            min: 0.0,
+            max: default_max(),
+            invert: false,
+            curve: Default::default(),
+        }
+    }
+
+    pub fn value(&self, depth: f32) -> f32 {
+        let mut value = self.curve.apply(travel(depth, self.deadzone));
+        if self.invert {
+            value = 1.0 - value;
+        }
+        (self.min + value * (self.max - self.min)).clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct Control {
+    pub channel: Channel,
+    pub config: ControlConfig,
+    pub value: f32,
+    // The 7-bit value last sent, used so we only send when the output actually changes
+    sent: Option<u8>,
+}
+
+impl Control {
+    pub fn new(channel: Channel, config: ControlConfig) -> Self {
+        Control {
+            channel,
+            config,
+            value: 0.0,
+            sent: None,
+        }
+    }
+
+    pub fn update_current_value(&mut self, new_value: f32, sink: &mut dyn NoteSink) -> Result<()> {
+        self.value = self.config.value(new_value);
+        let byte = (self.value * 127.0) as u8;
+        if self.sent != Some(byte) {
+            sink.control_change(self.config.control, self.value, self.channel)?;
+            self.sent = Some(byte);
+        }
+        Ok(())
+    }
+
+    // Puts the control back to where it rests with the key released, otherwise the receiver would be left at the last value
+    pub fn drop(&mut self, sink: Option<&mut dyn NoteSink>) -> Result<()> {
+        if let Some(sink) = sink {
+            if self.sent.is_some() {
+                self.update_current_value(0.0, sink)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BendDirection {
+    Up,
+    Down,
+}
+
+fn default_bend_range() -> u8 {
+    2
+}
+
+// Settings for a key which bends the pitch of its channel, pairing an `Up` key with a `Down` key gives the same behavior as a pitch wheel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PitchBendConfig {
+    pub direction: BendDirection,
+    // How far, in semitones, the key bends when fully pressed
+    #[serde(default = "default_bend_range")]
+    pub range: u8,
+    #[serde(default)]
+    pub deadzone: f32,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl PitchBendConfig {
+    pub fn new(direction: BendDirection, range: u8) -> Self {
+        PitchBendConfig {
+            direction,
+            range,
+            deadzone: 0.0,
+            curve: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Bend {
+    pub channel: Channel,
+    pub config: PitchBendConfig,
+    // How far the key is bending, between 0.0 and 1.0 of the configured range
+    pub value: f32,
+}
+
+impl Bend {
+    pub fn new(channel: Channel, config: PitchBendConfig) -> Self {
+        Bend {
+            channel,
+            config,
+            value: 0.0,
+        }
+    }
+
+    pub fn update_current_value(&mut self, new_value: f32) {
+        self.value = self
+            .config
+            .curve
+            .apply(travel(new_value, self.config.deadzone));
+    }
+
+    // Signed bend in semitones
+    pub fn semitones(&self) -> f32 {
+        let semitones = self.value * self.config.range as f32;
+        match self.config.direction {
+            BendDirection::Up => semitones,
+            BendDirection::Down => -semitones,
+        }
+    }
+}
+
+// Controller number of the sustain (damper) pedal
+pub const SUSTAIN_CC: u8 = 64;
+
+fn default_sustain_threshold() -> f32 {
+    0.5
+}
+
+// Settings for a key which acts as a sustain pedal
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SustainConfig {
+    // Depth past which the pedal counts as down
+    #[serde(default = "default_sustain_threshold")]
+    pub threshold: f32,
+    // Sends the depth as a continuous value for synths which support half pedaling, rather than just on/off
+    #[serde(default)]
+    pub half_pedal: bool,
+    // Only used with `half_pedal`
+    #[serde(default)]
+    pub deadzone: f32,
+    #[serde(default)]
+    pub curve: Curve,
+}
+
+impl Default for SustainConfig {
+    fn default() -> Self {
+        SustainConfig {
+            threshold: default_sustain_threshold(),
+            half_pedal: false,
+            deadzone: 0.0,
+            curve: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Sustain {
+    pub channel: Channel,
+    pub config: SustainConfig,
+    // Value of the pedal between 0.0 and 1.0
+    pub value: f32,
+    pub down: bool,
+}
+
+impl Sustain {
+    pub fn new(channel: Channel, config: SustainConfig) -> Self {
+        Sustain {
+            channel,
+            config,
+            value: 0.0,
+            down: false,
+        }
+    }
+
+    pub fn update_current_value(&mut self, new_value: f32) {
+        self.down = new_value >= self.config.threshold;
+        self.value = if self.config.half_pedal {
+            self.config
+                .curve
+                .apply(travel(new_value, self.config.deadzone))
+        } else if self.down {
+            1.0
+        } else {
+            0.0
+        };
+    }
+}
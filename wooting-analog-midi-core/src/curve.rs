@@ -0,0 +1,96 @@
+use serde::{Deserialize, Serialize};
+
+// Shapes an input between 0.0 and 1.0 into an output between 0.0 and 1.0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Curve {
+    #[default]
+    Linear,
+    // x^exponent, the larger the exponent the more effort is required to reach high values
+    Exponential {
+        exponent: f32,
+    },
+    // Inverse of the exponential curve, reaches high values quickly
+    Logarithmic {
+        exponent: f32,
+    },
+    // Flat at both ends and steep in the middle, a steepness of 1.0 is linear
+    SCurve {
+        steepness: f32,
+    },
+    // Always outputs the same value regardless of the input
+    Fixed {
+        value: f32,
+    },
+    // User defined points evenly spread across the input range, values in between are linearly interpolated
+    Table {
+        points: Vec<f32>,
+    },
+}
+
+impl Curve {
+    pub fn apply(&self, input: f32) -> f32 {
+        let x = input.clamp(0.0, 1.0);
+        let output = match self {
+            Curve::Linear => x,
+            Curve::Exponential { exponent } => x.powf(exponent.max(f32::EPSILON)),
+            Curve::Logarithmic { exponent } => 1.0 - (1.0 - x).powf(exponent.max(f32::EPSILON)),
+            Curve::SCurve { steepness } => {
+                let a = x.powf(steepness.max(f32::EPSILON));
+                let b = (1.0 - x).powf(steepness.max(f32::EPSILON));
+                a / (a + b)
+            }
+            Curve::Fixed { value } => *value,
+            Curve::Table { points } => match points.len() {
+                0 => x,
+                1 => points[0],
+                len => {
+                    let position = x * (len - 1) as f32;
+                    let index = (position.floor() as usize).min(len - 2);
+                    let fraction = position - index as f32;
+                    points[index] + (points[index + 1] - points[index]) * fraction
+                }
+            },
+        };
+        output.clamp(0.0, 1.0)
+    }
+}
+
+fn default_min() -> f32 {
+    0.0
+}
+
+fn default_max() -> f32 {
+    1.0
+}
+
+// Maps a measured velocity onto the range of velocities that actually get sent
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct VelocityCurve {
+    #[serde(default)]
+    pub curve: Curve,
+    #[serde(default = "default_min")]
+    pub min: f32,
+    #[serde(default = "default_max")]
+    pub max: f32,
+}
+
+impl VelocityCurve {
+    pub fn apply(&self, velocity: f32) -> f32 {
+        if let Curve::Fixed { value } = self.curve {
+            return value.clamp(0.0, 1.0);
+        }
+
+        self.min + self.curve.apply(velocity) * (self.max - self.min)
+    }
+}
+
+impl Default for VelocityCurve {
+    fn default() -> Self {
+        VelocityCurve {
+            curve: Default::default(),
+            min: default_min(),
+            max: default_max(),
+        }
+    }
+}
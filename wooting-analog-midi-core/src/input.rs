@@ -0,0 +1,96 @@
+use crate::output::is_virtual_port;
+use crate::Channel;
+use anyhow::Result;
+use midir::{Ignore, MidiInput, MidiInputConnection};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+
+const CLIENT_NAME: &str = "Wooting Analog MIDI Input";
+const CONNECTION_NAME: &str = "wooting-analog-midi-input";
+
+// External MIDI input whose channel messages are merged into our output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct InputConfig {
+    pub port: String,
+    // Only messages on these channels are passed through, all channels when not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub channels: Option<Vec<Channel>>,
+    // Input channel -> output channel, channels that aren't in here keep their channel
+    #[serde(default)]
+    pub remap: HashMap<Channel, Channel>,
+}
+
+impl InputConfig {
+    pub fn new(port: String) -> Self {
+        InputConfig {
+            port,
+            channels: None,
+            remap: HashMap::new(),
+        }
+    }
+
+    // Applies the channel filter & remapping, returning the message that should be sent on if any
+    pub fn process(&self, message: &[u8]) -> Option<Vec<u8>> {
+        let status = *message.first()?;
+        // Only channel messages get merged
+        if !(0x80..0xF0).contains(&status) {
+            return None;
+        }
+        let channel = status & 0x0F;
+        if let Some(channels) = &self.channels {
+            if !channels.contains(&channel) {
+                return None;
+            }
+        }
+        let channel = self.remap.get(&channel).cloned().unwrap_or(channel) & 0x0F;
+        let mut message = message.to_vec();
+        message[0] = (status & 0xF0) | channel;
+        Some(message)
+    }
+}
+
+fn midi_input() -> Result<MidiInput> {
+    let mut midi_in = MidiInput::new(CLIENT_NAME)?;
+    midi_in.ignore(Ignore::All);
+    Ok(midi_in)
+}
+
+// Names of the ports that can be used as the input
+pub fn input_port_names() -> Result<Vec<String>> {
+    let midi_in = midi_input()?;
+    Ok(midi_in
+        .ports()
+        .iter()
+        .filter_map(|port| midi_in.port_name(port).ok())
+        // Merging our own virtual output would feed it back into itself
+        .filter(|name| !is_virtual_port(name))
+        .collect())
+}
+
+// Connects to the input port, messages which pass the config's filter are sent to `messages` from midir's thread
+pub fn connect_input(
+    config: &InputConfig,
+    messages: Sender<Vec<u8>>,
+) -> Result<MidiInputConnection<()>> {
+    let midi_in = midi_input()?;
+    let port = midi_in
+        .ports()
+        .into_iter()
+        .find(|port| midi_in.port_name(port).ok().as_ref() == Some(&config.port))
+        .ok_or_else(|| anyhow!("Input port '{}' is not available", config.port))?;
+    let config = config.clone();
+    midi_in
+        .connect(
+            &port,
+            CONNECTION_NAME,
+            move |_, message, _| {
+                if let Some(message) = config.process(message) {
+                    // The receiving end only goes away when the input is being closed
+                    messages.send(message).ok();
+                }
+            },
+            (),
+        )
+        .map_err(|e| anyhow!("Error: {}", e))
+}
@@ -0,0 +1,177 @@
+use crate::{FromPrimitive, HIDCodes, NoteID};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// Rows of the ANSI layout from the number row down, each key's index in its row is its column. Every row sits about half a key to the right of the row above it, so the keys up-left & up-right of (row, column) are (row - 1, column) & (row - 1, column + 1)
+// Tab, Caps Lock & Left Shift are left out, they're much wider than the other keys and the shift is usually a modifier
+const ROWS: [&[Option<HIDCodes>]; 4] = [
+    &[
+        Some(HIDCodes::Backquote),
+        Some(HIDCodes::N1),
+        Some(HIDCodes::N2),
+        Some(HIDCodes::N3),
+        Some(HIDCodes::N4),
+        Some(HIDCodes::N5),
+        Some(HIDCodes::N6),
+        Some(HIDCodes::N7),
+        Some(HIDCodes::N8),
+        Some(HIDCodes::N9),
+        Some(HIDCodes::N0),
+        Some(HIDCodes::Minus),
+        Some(HIDCodes::Equal),
+    ],
+    &[
+        None,
+        Some(HIDCodes::Q),
+        Some(HIDCodes::W),
+        Some(HIDCodes::E),
+        Some(HIDCodes::R),
+        Some(HIDCodes::T),
+        Some(HIDCodes::Y),
+        Some(HIDCodes::U),
+        Some(HIDCodes::I),
+        Some(HIDCodes::O),
+        Some(HIDCodes::P),
+        Some(HIDCodes::BracketLeft),
+        Some(HIDCodes::BracketRight),
+        Some(HIDCodes::Backslash),
+    ],
+    &[
+        None,
+        Some(HIDCodes::A),
+        Some(HIDCodes::S),
+        Some(HIDCodes::D),
+        Some(HIDCodes::F),
+        Some(HIDCodes::G),
+        Some(HIDCodes::H),
+        Some(HIDCodes::J),
+        Some(HIDCodes::K),
+        Some(HIDCodes::L),
+        Some(HIDCodes::Semicolon),
+        Some(HIDCodes::Quote),
+    ],
+    &[
+        None,
+        Some(HIDCodes::Z),
+        Some(HIDCodes::X),
+        Some(HIDCodes::C),
+        Some(HIDCodes::V),
+        Some(HIDCodes::B),
+        Some(HIDCodes::N),
+        Some(HIDCodes::M),
+        Some(HIDCodes::Comma),
+        Some(HIDCodes::Period),
+        Some(HIDCodes::Slash),
+    ],
+];
+
+// Semitones above C of the white keys
+const WHITE_KEYS: [i16; 7] = [0, 2, 4, 5, 7, 9, 11];
+
+// (row, column) of the key on the grid, if it's part of it
+pub fn key_position(key: HIDCodes) -> Option<(i16, i16)> {
+    ROWS.iter().enumerate().find_map(|(row, keys)| {
+        keys.iter()
+            .position(|code| code.as_ref() == Some(&key))
+            .map(|column| (row as i16, column as i16))
+    })
+}
+
+fn grid_keys() -> impl Iterator<Item = (HIDCodes, i16, i16)> {
+    ROWS.iter().enumerate().flat_map(|(row, keys)| {
+        keys.iter()
+            .enumerate()
+            .filter_map(move |(column, code)| code.map(|code| (code, row as i16, column as i16)))
+    })
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Layout {
+    // Whole tone to the right, fifth up-right & fourth up-left
+    WickiHayden,
+    // Whole tones along the rows, with every other row a semitone higher
+    Janko,
+    // Fifth to the right, major third up-right & minor third down-right
+    HarmonicTable,
+    // Semitones along the rows, with each row a fourth above the one below it like the strings of a guitar
+    Fourths,
+    // White keys on the origin's row with the black keys on the row above, the next two rows up are an octave higher
+    Piano,
+}
+
+impl Layout {
+    // Semitones moved by going one key to the right and one key up-right, for the isomorphic layouts
+    fn steps(&self) -> Option<(i16, i16)> {
+        match self {
+            Layout::WickiHayden => Some((2, 7)),
+            Layout::Janko => Some((2, 1)),
+            Layout::HarmonicTable => Some((7, 4)),
+            Layout::Fourths => Some((1, 5)),
+            Layout::Piano => None,
+        }
+    }
+}
+
+// Settings for generating a mapping for the whole keyboard
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LayoutConfig {
+    pub layout: Layout,
+    // HID code of the key which plays `base_note`
+    pub origin: u8,
+    pub base_note: NoteID,
+}
+
+impl LayoutConfig {
+    fn note_at(
+        &self,
+        (origin_row, origin_column): (i16, i16),
+        row: i16,
+        column: i16,
+    ) -> Option<i16> {
+        let base = self.base_note as i16;
+        let rows_up = origin_row - row;
+        let columns = column - origin_column;
+        match self.layout.steps() {
+            Some((right, up_right)) => {
+                // Going up-left is going up-right then back to the left
+                Some(base + columns * right + rows_up * (up_right - right))
+            }
+            None => {
+                let octave = rows_up.div_euclid(2);
+                let white_key = |degree: i16| {
+                    base + 12 * (octave + degree.div_euclid(7))
+                        + WHITE_KEYS[degree.rem_euclid(7) as usize]
+                };
+                if rows_up.rem_euclid(2) == 0 {
+                    Some(white_key(columns))
+                } else {
+                    // The key sits between the white keys down-left & down-right of it, and is only a black key if they're a whole tone apart
+                    let below = white_key(columns - 1);
+                    if white_key(columns) - below == 2 {
+                        Some(below + 1)
+                    } else {
+                        None
+                    }
+                }
+            }
+        }
+    }
+
+    // Notes for every key on the grid which ends up inside the MIDI range
+    pub fn generate(&self) -> Result<Vec<(HIDCodes, NoteID)>> {
+        let origin = HIDCodes::from_u8(self.origin)
+            .and_then(key_position)
+            .ok_or_else(|| anyhow!("Key {} can't be used as the origin", self.origin))?;
+        Ok(grid_keys()
+            .filter_map(|(code, row, column)| {
+                let note = self.note_at(origin, row, column)?;
+                if (0..=127).contains(&note) {
+                    Some((code, note as NoteID))
+                } else {
+                    None
+                }
+            })
+            .collect())
+    }
+}
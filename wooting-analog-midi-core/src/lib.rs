@@ -7,38 +7,83 @@ extern crate lazy_static;
 extern crate anyhow;
 
 use log::*;
-use sdk::SDKResult;
-pub use sdk::{DeviceInfo, FromPrimitive, HIDCodes, ToPrimitive, WootingAnalogResult};
+pub use sdk::{
+    DeviceEventType, DeviceID, DeviceInfo, DeviceType, FromPrimitive, HIDCodes, ToPrimitive,
+    WootingAnalogResult,
+};
 use wooting_analog_wrapper as sdk;
 
+mod arpeggiator;
+mod chord;
+mod control;
+mod curve;
+mod input;
+mod layout;
+mod modifier;
+mod mpe;
+mod output;
+mod scale;
+mod source;
+pub use arpeggiator::{ArpConfig, ArpMode};
+pub use chord::{ChordConfig, ChordQuality, ChordVoicing};
+pub use control::{
+    Bend, BendDirection, Control, ControlConfig, PitchBendConfig, Sustain, SustainConfig,
+    SUSTAIN_CC,
+};
+pub use curve::{Curve, VelocityCurve};
+pub use input::InputConfig;
+pub use layout::{key_position, Layout, LayoutConfig};
+pub use modifier::{Modifier, ModifierConfig, ModifierMode};
+pub use mpe::{MpeConfig, MpeZone};
+pub use output::{Output, OutputConfig, OutputRouting, VIRTUAL_PORT_NAME};
+pub use scale::{Scale, ScaleConfig, Transpose, TransposeMode};
+pub use source::{AnalogSource, DeviceEventCallback, ScriptedSource, SdkSource};
+
 use anyhow::{Context, Result};
-use midir::{MidiOutput, MidiOutputConnection};
+use arpeggiator::ArpThread;
+use midir::{MidiInputConnection, MidiOutputConnection, MidiOutputPort};
+use output::Outputs;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 use std::time::Instant;
 
 const DEVICE_BUFFER_MAX: usize = 5;
+// Used for the key state when the source can't tell us which devices are connected, so everything is read as one merged buffer
+pub const MERGED_DEVICE: DeviceID = 0;
 const ANALOG_BUFFER_READ_MAX: usize = 40;
 const NOTE_ON_MSG: u8 = 0x90;
 const NOTE_OFF_MSG: u8 = 0x80;
 const POLY_AFTERTOUCH_MSG: u8 = 0xA0;
+const CONTROL_CHANGE_MSG: u8 = 0xB0;
+const CHANNEL_PRESSURE_MSG: u8 = 0xD0;
+const PITCH_BEND_MSG: u8 = 0xE0;
+const PITCH_BEND_CENTER: u16 = 0x2000;
+// Controller numbers used to set the pitch bend range through the Registered Parameter (RPN) 0
+const RPN_MSB_CC: u8 = 101;
+const RPN_LSB_CC: u8 = 100;
+const DATA_ENTRY_MSB_CC: u8 = 6;
+const DATA_ENTRY_LSB_CC: u8 = 38;
+const RPN_NULL: u8 = 127;
 // const VELOCITY: u8 = 0x64;
 // The analog threshold at which we consider a note being turned on
 // const THRESHOLD: f32 = 0.5;
-// What counts as a key being pressed. Currently used for modifier press detection
+// What counts as a key being pressed. Currently used as the default for modifier press detection
 const ACTUATION_POINT: f32 = 0.2;
-const MODIFIER_KEY: HIDCodes = HIDCodes::LeftShift;
-const AFTERTOUCH: bool = true;
 // How many times a second we'll check for updates on how much keys are pressed
 pub const REFRESH_RATE: f32 = 100.0; //Hz
 const MIDI_NOTE_MAX: u8 = 108;
 const MIDI_NOTE_MIN: u8 = 21;
+// Release velocity used when a note is released without the key moving, e.g. when the mapping changes. This is the value the MIDI spec recommends for devices which don't measure release velocity
+const DEFAULT_RELEASE_VELOCITY: f32 = 0.5;
 
 // NoteID Reference: https://newt.phys.unsw.edu.au/jw/notes.html
 pub type NoteID = u8;
 pub type Channel = u8;
 
-trait NoteSink {
+pub trait NoteSink: Send {
     fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()>;
     fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()>;
     fn polyphonic_aftertouch(
@@ -47,6 +92,13 @@ trait NoteSink {
         pressure: f32,
         channel: Channel,
     ) -> Result<()>;
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()>;
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()>;
+    // Value between -1.0 (full bend down) and 1.0 (full bend up)
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()>;
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()>;
+    // A complete message which gets passed on as is, e.g. one received from the MIDI input
+    fn raw_message(&mut self, message: &[u8]) -> Result<()>;
 }
 
 impl NoteSink for MidiOutputConnection {
@@ -75,6 +127,236 @@ impl NoteSink for MidiOutputConnection {
         ])?;
         Ok(())
     }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.send(&[
+            CHANNEL_PRESSURE_MSG | channel,
+            (f32::min(pressure, 1.0) * 127.0) as u8,
+        ])?;
+        Ok(())
+    }
+
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()> {
+        self.send(&[
+            CONTROL_CHANGE_MSG | channel,
+            control,
+            (f32::min(value, 1.0) * 127.0) as u8,
+        ])?;
+        Ok(())
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        let bend = (PITCH_BEND_CENTER as f32 + value.clamp(-1.0, 1.0) * 8191.0) as u16;
+        self.send(&[
+            PITCH_BEND_MSG | channel,
+            (bend & 0x7F) as u8,
+            ((bend >> 7) & 0x7F) as u8,
+        ])?;
+        Ok(())
+    }
+
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()> {
+        let status = CONTROL_CHANGE_MSG | channel;
+        self.send(&[status, RPN_MSB_CC, 0])?;
+        self.send(&[status, RPN_LSB_CC, 0])?;
+        self.send(&[status, DATA_ENTRY_MSB_CC, semitones.min(127)])?;
+        self.send(&[status, DATA_ENTRY_LSB_CC, 0])?;
+        // Deselect the RPN so stray data entry messages don't change it
+        self.send(&[status, RPN_MSB_CC, RPN_NULL])?;
+        self.send(&[status, RPN_LSB_CC, RPN_NULL])?;
+        Ok(())
+    }
+
+    fn raw_message(&mut self, message: &[u8]) -> Result<()> {
+        self.send(message)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SinkMessage {
+    NoteOn {
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    },
+    NoteOff {
+        note_id: NoteID,
+        velocity: f32,
+        channel: Channel,
+    },
+    PolyphonicAftertouch {
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    },
+    ChannelPressure {
+        pressure: f32,
+        channel: Channel,
+    },
+    ControlChange {
+        control: u8,
+        value: f32,
+        channel: Channel,
+    },
+    PitchBend {
+        value: f32,
+        channel: Channel,
+    },
+    PitchBendRange {
+        semitones: u8,
+        channel: Channel,
+    },
+    Raw {
+        message: Vec<u8>,
+    },
+}
+
+// Sink which keeps every message it receives along with the time it was received. Clones share the same message log, so a clone can be handed to the `MidiService` while the original is used to inspect what was sent
+#[derive(Debug, Clone, Default)]
+pub struct RecordingSink {
+    messages: Arc<Mutex<Vec<(Instant, SinkMessage)>>>,
+}
+
+impl RecordingSink {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn messages(&self) -> Vec<(Instant, SinkMessage)> {
+        self.messages.lock().unwrap().clone()
+    }
+
+    pub fn take_messages(&self) -> Vec<(Instant, SinkMessage)> {
+        self.messages.lock().unwrap().drain(..).collect()
+    }
+
+    pub fn clear(&self) {
+        self.messages.lock().unwrap().clear();
+    }
+
+    fn record(&self, message: SinkMessage) {
+        self.messages
+            .lock()
+            .unwrap()
+            .push((Instant::now(), message));
+    }
+}
+
+impl NoteSink for RecordingSink {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::NoteOn {
+            note_id,
+            velocity,
+            channel,
+        });
+        Ok(())
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::NoteOff {
+            note_id,
+            velocity,
+            channel,
+        });
+        Ok(())
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        self.record(SinkMessage::PolyphonicAftertouch {
+            note_id,
+            pressure,
+            channel,
+        });
+        Ok(())
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::ChannelPressure { pressure, channel });
+        Ok(())
+    }
+
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::ControlChange {
+            control,
+            value,
+            channel,
+        });
+        Ok(())
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::PitchBend { value, channel });
+        Ok(())
+    }
+
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()> {
+        self.record(SinkMessage::PitchBendRange { semitones, channel });
+        Ok(())
+    }
+
+    fn raw_message(&mut self, message: &[u8]) -> Result<()> {
+        self.record(SinkMessage::Raw {
+            message: message.to_vec(),
+        });
+        Ok(())
+    }
+}
+
+// How the depth of a key is reported while the note is held
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PressureMode {
+    Off,
+    #[default]
+    PolyAftertouch,
+    // Sends the highest pressure out of all held notes on the channel
+    ChannelPressure,
+    // Same as channel pressure, but sent as the given control change
+    ControlChange {
+        control: u8,
+    },
+}
+
+// What happens to a held note when the transposition changes
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransposePolicy {
+    // Keep the pitch the note was triggered with until it's released
+    #[default]
+    Latch,
+    // Release the held note and trigger it again at the new pitch
+    Retrigger,
+}
+
+fn default_rapid_trigger_distance() -> f32 {
+    0.1
+}
+
+// Rapid trigger turns a held note off as soon as the key moves up by `release_distance` and back on when it moves down by `press_distance`, regardless of the threshold. Once the key is fully released the threshold applies again
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RapidTrigger {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_rapid_trigger_distance")]
+    pub release_distance: f32,
+    #[serde(default = "default_rapid_trigger_distance")]
+    pub press_distance: f32,
+}
+
+impl Default for RapidTrigger {
+    fn default() -> Self {
+        RapidTrigger {
+            enabled: false,
+            release_distance: default_rapid_trigger_distance(),
+            press_distance: default_rapid_trigger_distance(),
+        }
+    }
 }
 
 fn default_threshold() -> f32 {
@@ -91,6 +373,27 @@ pub struct NoteConfig {
     threshold: f32,
     #[serde(default = "default_velocity_scale")]
     velocity_scale: f32,
+    #[serde(default)]
+    velocity_curve: VelocityCurve,
+    #[serde(default = "default_velocity_scale")]
+    release_velocity_scale: f32,
+    #[serde(default)]
+    release_velocity_curve: VelocityCurve,
+    #[serde(default)]
+    pressure_mode: PressureMode,
+    // Rescales the pressure so it starts at 0 at the threshold, rather than using the raw depth of the key
+    #[serde(default)]
+    pressure_from_threshold: bool,
+    #[serde(default)]
+    transpose_policy: TransposePolicy,
+    // How far below the threshold the key has to rise before the note is turned off, avoids retriggers when resting near the threshold
+    #[serde(default)]
+    hysteresis: f32,
+    // Any depth below this is treated as the key not being pressed at all, to filter out sensor jitter
+    #[serde(default)]
+    noise_floor: f32,
+    #[serde(default)]
+    rapid_trigger: RapidTrigger,
     // Any new properties should have a default added to it to ensure old configs get pulled in properly
 }
 
@@ -99,9 +402,45 @@ impl NoteConfig {
         NoteConfig {
             threshold,
             velocity_scale,
+            velocity_curve: Default::default(),
+            release_velocity_scale: default_velocity_scale(),
+            release_velocity_curve: Default::default(),
+            pressure_mode: Default::default(),
+            pressure_from_threshold: false,
+            transpose_policy: Default::default(),
+            hysteresis: 0.0,
+            noise_floor: 0.0,
+            rapid_trigger: Default::default(),
         }
     }
 
+    pub fn with_rapid_trigger(mut self, rapid_trigger: RapidTrigger) -> Self {
+        self.rapid_trigger = rapid_trigger;
+        self
+    }
+
+    pub fn with_hysteresis(mut self, hysteresis: f32, noise_floor: f32) -> Self {
+        self.hysteresis = hysteresis;
+        self.noise_floor = noise_floor;
+        self
+    }
+
+    pub fn with_transpose_policy(mut self, transpose_policy: TransposePolicy) -> Self {
+        self.transpose_policy = transpose_policy;
+        self
+    }
+
+    pub fn with_velocity_curve(mut self, velocity_curve: VelocityCurve) -> Self {
+        self.velocity_curve = velocity_curve;
+        self
+    }
+
+    pub fn with_release_velocity(mut self, scale: f32, curve: VelocityCurve) -> Self {
+        self.release_velocity_scale = scale;
+        self.release_velocity_curve = curve;
+        self
+    }
+
     pub fn threshold(&self) -> &f32 {
         &self.threshold
     }
@@ -109,6 +448,72 @@ impl NoteConfig {
     pub fn velocity_scale(&self) -> &f32 {
         &self.velocity_scale
     }
+
+    pub fn velocity_curve(&self) -> &VelocityCurve {
+        &self.velocity_curve
+    }
+
+    pub fn with_pressure(mut self, mode: PressureMode, from_threshold: bool) -> Self {
+        self.pressure_mode = mode;
+        self.pressure_from_threshold = from_threshold;
+        self
+    }
+
+    pub fn release_velocity_scale(&self) -> &f32 {
+        &self.release_velocity_scale
+    }
+
+    pub fn release_velocity_curve(&self) -> &VelocityCurve {
+        &self.release_velocity_curve
+    }
+
+    pub fn pressure_mode(&self) -> &PressureMode {
+        &self.pressure_mode
+    }
+
+    pub fn pressure_from_threshold(&self) -> &bool {
+        &self.pressure_from_threshold
+    }
+
+    pub fn transpose_policy(&self) -> &TransposePolicy {
+        &self.transpose_policy
+    }
+
+    pub fn hysteresis(&self) -> &f32 {
+        &self.hysteresis
+    }
+
+    pub fn noise_floor(&self) -> &f32 {
+        &self.noise_floor
+    }
+
+    pub fn rapid_trigger(&self) -> &RapidTrigger {
+        &self.rapid_trigger
+    }
+
+    // The depth a held note has to rise above to be turned off
+    pub fn release_threshold(&self) -> f32 {
+        f32::max(self.threshold - self.hysteresis, 0.0)
+    }
+
+    pub fn filter_noise(&self, value: f32) -> f32 {
+        if value < self.noise_floor {
+            0.0
+        } else {
+            value
+        }
+    }
+
+    pub fn pressure(&self, value: f32) -> f32 {
+        if self.pressure_from_threshold {
+            if self.threshold >= 1.0 {
+                return 0.0;
+            }
+            ((value - self.threshold) / (1.0 - self.threshold)).clamp(0.0, 1.0)
+        } else {
+            value
+        }
+    }
 }
 
 impl Default for NoteConfig {
@@ -117,14 +522,128 @@ impl Default for NoteConfig {
     }
 }
 
+// Per key/mapping replacement for values of the global `NoteConfig`, anything left as `None` falls back to the config it's applied on top of
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct NoteConfigOverride {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threshold: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity_scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub velocity_curve: Option<VelocityCurve>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_velocity_scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub release_velocity_curve: Option<VelocityCurve>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure_mode: Option<PressureMode>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pressure_from_threshold: Option<bool>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub transpose_policy: Option<TransposePolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hysteresis: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub noise_floor: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rapid_trigger: Option<RapidTrigger>,
+}
+
+impl NoteConfigOverride {
+    pub fn is_empty(&self) -> bool {
+        self.threshold.is_none()
+            && self.velocity_scale.is_none()
+            && self.velocity_curve.is_none()
+            && self.release_velocity_scale.is_none()
+            && self.release_velocity_curve.is_none()
+            && self.pressure_mode.is_none()
+            && self.pressure_from_threshold.is_none()
+            && self.transpose_policy.is_none()
+            && self.hysteresis.is_none()
+            && self.noise_floor.is_none()
+            && self.rapid_trigger.is_none()
+    }
+
+    pub fn apply<'a>(&self, config: &'a NoteConfig) -> Cow<'a, NoteConfig> {
+        if self.is_empty() {
+            return Cow::Borrowed(config);
+        }
+
+        let mut config = config.clone();
+        if let Some(threshold) = self.threshold {
+            config.threshold = threshold;
+        }
+        if let Some(velocity_scale) = self.velocity_scale {
+            config.velocity_scale = velocity_scale;
+        }
+        if let Some(velocity_curve) = &self.velocity_curve {
+            config.velocity_curve = velocity_curve.clone();
+        }
+        if let Some(release_velocity_scale) = self.release_velocity_scale {
+            config.release_velocity_scale = release_velocity_scale;
+        }
+        if let Some(release_velocity_curve) = &self.release_velocity_curve {
+            config.release_velocity_curve = release_velocity_curve.clone();
+        }
+        if let Some(pressure_mode) = self.pressure_mode {
+            config.pressure_mode = pressure_mode;
+        }
+        if let Some(pressure_from_threshold) = self.pressure_from_threshold {
+            config.pressure_from_threshold = pressure_from_threshold;
+        }
+        if let Some(transpose_policy) = self.transpose_policy {
+            config.transpose_policy = transpose_policy;
+        }
+        if let Some(hysteresis) = self.hysteresis {
+            config.hysteresis = hysteresis;
+        }
+        if let Some(noise_floor) = self.noise_floor {
+            config.noise_floor = noise_floor;
+        }
+        if let Some(rapid_trigger) = self.rapid_trigger {
+            config.rapid_trigger = rapid_trigger;
+        }
+        Cow::Owned(config)
+    }
+}
+
+// Overrides for a single key, `channels` allows the mapping of the key on a specific channel to be overriden further
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct KeyConfig {
+    #[serde(flatten)]
+    pub note_config: NoteConfigOverride,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub channels: HashMap<Channel, NoteConfigOverride>,
+}
+
+impl KeyConfig {
+    pub fn is_empty(&self) -> bool {
+        self.note_config.is_empty() && self.channels.values().all(|c| c.is_empty())
+    }
+}
+
 #[derive(Debug)]
 pub struct Note {
     pub note_id: NoteID,
     pub pressed: bool,
-    shifted_amount: i8,
+    // The note which has actually been sent the note on, if any
+    pub sent_note: Option<NoteID>,
+    // Scale degree the note is mapped to, in which case `note_id` follows the scale
+    pub degree: Option<i16>,
+    // The pitch the note would be played at with the transposition it's latched to
+    transposed_note: Option<NoteID>,
     pub velocity: f32,
+    // Velocity the current note on was sent with
+    pub on_velocity: f32,
+    pub release_velocity: f32,
+    pub pressure: f32,
+    pub pressure_mode: PressureMode,
     pub channel: Channel,
     pub lower_press_time: Option<(Instant, f32)>,
+    // Time and depth from which the key started travelling back up while the note is on
+    pub release_start: Option<(Instant, f32)>,
+    // While rapid trigger is active this is the deepest point since the note on, or the shallowest point since the note off
+    rapid_extreme: Option<f32>,
 }
 
 impl Note {
@@ -132,28 +651,68 @@ impl Note {
         Note {
             note_id: note,
             pressed: false,
+            sent_note: None,
+            degree: None,
+            transposed_note: Some(note),
             velocity: 0.0,
-            shifted_amount: 0,
+            on_velocity: 0.0,
+            release_velocity: 0.0,
+            pressure: 0.0,
+            pressure_mode: Default::default(),
             channel,
             lower_press_time: None,
+            release_start: None,
+            rapid_extreme: None,
+        }
+    }
+
+    pub fn with_degree(channel: Channel, degree: i16, scale: &ScaleConfig) -> Note {
+        let mut note = Note::new(channel, scale.note(degree).unwrap_or(0));
+        note.degree = Some(degree);
+        note.transposed_note = scale.note(degree);
+        note
+    }
+
+    fn transpose(&mut self, transpose: &Transpose) -> Option<NoteID> {
+        match self.degree {
+            Some(degree) => {
+                // Keeps `note_id` in line with the current root & scale
+                if let Some(note_id) = transpose.scale.note(degree) {
+                    self.note_id = note_id;
+                }
+                transpose.apply_degree(degree)
+            }
+            None => transpose.apply(self.note_id),
         }
     }
 
     fn get_effective_note(&self) -> Option<NoteID> {
-        let computed = self.note_id as i16 + self.shifted_amount as i16;
-        if computed >= MIDI_NOTE_MIN.into() && computed <= MIDI_NOTE_MAX.into() {
-            Some(computed as NoteID)
-        } else {
-            None
+        self.transposed_note
+            .filter(|note| (MIDI_NOTE_MIN..=MIDI_NOTE_MAX).contains(note))
+    }
+
+    fn measure_release_velocity(&self, new_value: f32, note_config: &NoteConfig) -> f32 {
+        match self.release_start {
+            Some((start_time, start_depth)) if start_depth > new_value => {
+                let duration = start_time.elapsed().as_secs_f32();
+                if duration > 0.0 {
+                    (((start_depth - new_value) / duration)
+                        * (note_config.release_velocity_scale() / 100.0))
+                        .clamp(0.0, 1.0)
+                } else {
+                    1.0
+                }
+            }
+            _ => 0.0,
         }
     }
 
-    fn update_current_value(
+    pub fn update_current_value(
         &mut self,
         previous_value: f32,
         new_value: f32,
-        sink: &mut impl NoteSink,
-        shifted_amount: i8,
+        sink: &mut dyn NoteSink,
+        transpose: &Transpose,
         note_config: &NoteConfig,
     ) -> Result<()> {
         // if new_value == 0.0 {
@@ -200,55 +759,125 @@ impl Note {
             }
         }
 
-        // If the modifier pressed state has changed we need to make sure we turn the current note off because the note id will be changed
-        if shifted_amount != self.shifted_amount && !self.pressed {
-            // if self.pressed {
-            //     if let Some(effective_note) = self.get_effective_note() {
-            //         sink.note_off(effective_note, self.velocity, self.channel)?;
-            //     }
-            //     self.pressed = false;
-            // }
-            self.shifted_amount = shifted_amount;
+        let transposed_note = self.transpose(transpose);
+        match note_config.transpose_policy() {
+            // The pitch is only allowed to change while the note isn't held
+            TransposePolicy::Latch => {
+                if !self.pressed {
+                    self.transposed_note = transposed_note;
+                }
+            }
+            // The held note gets retriggered below if the pitch changes
+            TransposePolicy::Retrigger => self.transposed_note = transposed_note,
+        }
+
+        // Whenever the key isn't moving up the release hasn't started yet, so we move the starting point of the release along with it
+        if self.pressed && (self.release_start.is_none() || new_value >= previous_value) {
+            self.release_start = Some((Instant::now(), new_value));
         }
 
-        if let Some(effective_note) = self.get_effective_note() {
-            if new_value > *note_config.threshold() {
-                // 'Pressed'
-                if !self.pressed {
-                    info!(
-                        "Triggering with velocity {:?}, prev {:?}, new_val {:?}, elapsed {:?}",
-                        self.velocity,
-                        self.lower_press_time,
-                        new_value,
-                        self.lower_press_time.unwrap().0.elapsed()
-                    );
-                    sink.note_on(effective_note, self.velocity, self.channel)?;
-                    self.pressed = true;
+        self.pressure_mode = *note_config.pressure_mode();
+        let rapid_trigger = note_config.rapid_trigger();
+        let was_pressed = self.pressed;
+        let held = match self.rapid_extreme {
+            Some(extreme) if rapid_trigger.enabled => {
+                if self.pressed {
+                    new_value > 0.0 && new_value > extreme - rapid_trigger.release_distance
                 } else {
-                    // While we are in the range of what we consider 'pressed' for the key & the note on has already been sent we send aftertouch
-                    if AFTERTOUCH && new_value != previous_value {
-                        sink.polyphonic_aftertouch(effective_note, new_value, self.channel)?;
-                    }
+                    new_value >= extreme + rapid_trigger.press_distance
                 }
-            } else {
-                // 'Not Pressed'
+            }
+            _ => {
                 if self.pressed {
-                    sink.note_off(effective_note, self.velocity, self.channel)?;
-                    self.pressed = false;
+                    new_value > note_config.release_threshold()
+                } else {
+                    new_value > *note_config.threshold()
+                }
+            }
+        };
+        if held {
+            self.pressure = note_config.pressure(new_value);
+            // 'Pressed'
+            if !self.pressed {
+                info!(
+                    "Triggering with velocity {:?}, prev {:?}, new_val {:?}, elapsed {:?}",
+                    self.velocity,
+                    self.lower_press_time,
+                    new_value,
+                    self.lower_press_time.map(|(time, _)| time.elapsed())
+                );
+                self.on_velocity = note_config.velocity_curve().apply(self.velocity);
+                // If the shift puts the note out of range the key is still considered pressed, it just doesn't sound
+                if let Some(effective_note) = self.get_effective_note() {
+                    sink.note_on(effective_note, self.on_velocity, self.channel)?;
+                    self.sent_note = Some(effective_note);
+                }
+                self.pressed = true;
+                self.release_start = Some((Instant::now(), new_value));
+            } else if self.sent_note != self.get_effective_note() {
+                // The transposition has changed while the note is held, so the note gets moved over to the new pitch
+                if let Some(sent_note) = self.sent_note.take() {
+                    sink.note_off(sent_note, DEFAULT_RELEASE_VELOCITY, self.channel)?;
+                }
+                if let Some(effective_note) = self.get_effective_note() {
+                    sink.note_on(effective_note, self.on_velocity, self.channel)?;
+                    self.sent_note = Some(effective_note);
+                }
+            } else if let Some(sent_note) = self.sent_note {
+                // While we are in the range of what we consider 'pressed' for the key & the note on has already been sent we send aftertouch
+                // Channel wide pressure gets sent by the `MidiService` as it's shared between all notes on the channel
+                if self.pressure_mode == PressureMode::PolyAftertouch && new_value != previous_value
+                {
+                    sink.polyphonic_aftertouch(sent_note, self.pressure, self.channel)?;
+                }
+            }
+        } else {
+            // 'Not Pressed'
+            if self.pressed {
+                self.release_velocity = self.measure_release_velocity(new_value, note_config);
+                // The note off always has to go to the note we sent the note on for, even if the transposition has changed since
+                if let Some(sent_note) = self.sent_note.take() {
+                    sink.note_off(
+                        sent_note,
+                        note_config
+                            .release_velocity_curve()
+                            .apply(self.release_velocity),
+                        self.channel,
+                    )?;
                 }
+                self.pressed = false;
+                self.release_start = None;
             }
+            self.pressure = 0.0;
         }
 
+        self.rapid_extreme = if !rapid_trigger.enabled || new_value <= 0.0 {
+            None
+        } else if self.pressed {
+            match self.rapid_extreme {
+                Some(peak) if was_pressed => Some(f32::max(peak, new_value)),
+                _ => Some(new_value),
+            }
+        } else {
+            match self.rapid_extreme {
+                Some(_) if was_pressed => Some(new_value),
+                Some(trough) => Some(f32::min(trough, new_value)),
+                // The key hasn't been pressed past the threshold yet
+                None => None,
+            }
+        };
+
         Ok(())
     }
 
-    fn drop(&mut self, sink: &mut Option<impl NoteSink>) -> Result<()> {
+    fn drop(&mut self, sink: Option<&mut dyn NoteSink>) -> Result<()> {
         if let Some(sink) = sink {
             if self.pressed {
-                if let Some(effective_note) = self.get_effective_note() {
-                    sink.note_off(effective_note, self.velocity, self.channel)?;
+                if let Some(sent_note) = self.sent_note.take() {
+                    sink.note_off(sent_note, DEFAULT_RELEASE_VELOCITY, self.channel)?;
                 }
                 self.pressed = false;
+                self.release_start = None;
             }
         }
 
@@ -256,37 +885,77 @@ impl Note {
     }
 }
 
+// What a key does on a given channel
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Mapping {
+    Note { note: NoteID },
+    // Degree of the scale the `MidiService` is set to, 0 is the root
+    Degree { degree: i16 },
+    // Every tone of the chord is played by the key, so they share its velocity & pressure
+    Chord(ChordConfig),
+    Control(ControlConfig),
+    PitchBend(PitchBendConfig),
+    Sustain(SustainConfig),
+}
+
 #[derive(Debug)]
 pub struct Key {
     pub notes: Vec<Note>,
+    pub controls: Vec<Control>,
+    pub bends: Vec<Bend>,
+    pub sustains: Vec<Sustain>,
     pub current_value: f32,
+    pub config: KeyConfig,
 }
 
 impl Key {
     fn new() -> Self {
         Self {
             notes: vec![],
+            controls: vec![],
+            bends: vec![],
+            sustains: vec![],
             current_value: 0.0,
+            config: Default::default(),
         }
     }
 
-    fn update_value(
+    pub fn update_value(
         &mut self,
         new_value: f32,
-        sink: &mut impl NoteSink,
-        shifted_amount: i8,
+        sink: &mut dyn NoteSink,
+        transpose: &Transpose,
         note_config: &NoteConfig,
     ) -> Result<()> {
+        let key_config = self.config.note_config.apply(note_config);
+        let new_value = key_config.filter_noise(new_value);
         for note in self.notes.iter_mut() {
+            let note_config = match self.config.channels.get(&note.channel) {
+                Some(channel_config) => channel_config.apply(&key_config),
+                None => Cow::Borrowed(key_config.as_ref()),
+            };
             note.update_current_value(
                 self.current_value,
                 new_value,
                 sink,
-                shifted_amount,
-                note_config,
+                transpose,
+                &note_config,
             )?;
         }
 
+        for control in self.controls.iter_mut() {
+            control.update_current_value(new_value, sink)?;
+        }
+
+        // Bends & sustain pedals from all keys on a channel get combined, so they're sent by the `MidiService`
+        for bend in self.bends.iter_mut() {
+            bend.update_current_value(new_value);
+        }
+        for sustain in self.sustains.iter_mut() {
+            sustain.update_current_value(new_value);
+        }
+
         self.current_value = new_value;
 
         Ok(())
@@ -294,15 +963,39 @@ impl Key {
 
     fn update_mappings(
         &mut self,
-        mappings: &Vec<(Channel, NoteID)>,
-        sink: &mut Option<impl NoteSink>,
+        mappings: &[(Channel, Mapping)],
+        scale: &ScaleConfig,
+        mut sink: Option<&mut dyn NoteSink>,
     ) -> Result<()> {
         for mut note in self.notes.drain(..) {
-            note.drop(sink)?;
+            note.drop(sink.as_mut().map(|sink| &mut **sink as &mut dyn NoteSink))?;
         }
+        for mut control in self.controls.drain(..) {
+            control.drop(sink.as_mut().map(|sink| &mut **sink as &mut dyn NoteSink))?;
+        }
+        self.bends.clear();
+        self.sustains.clear();
 
-        for (channel, note_id) in mappings.iter() {
-            self.notes.push(Note::new(*channel, *note_id));
+        for (channel, mapping) in mappings.iter() {
+            match mapping {
+                Mapping::Note { note } => self.notes.push(Note::new(*channel, *note)),
+                Mapping::Degree { degree } => {
+                    self.notes.push(Note::with_degree(*channel, *degree, scale))
+                }
+                Mapping::Chord(config) => self.notes.extend(
+                    config
+                        .notes()
+                        .into_iter()
+                        .map(|note| Note::new(*channel, note)),
+                ),
+                Mapping::Control(config) => {
+                    self.controls.push(Control::new(*channel, config.clone()))
+                }
+                Mapping::PitchBend(config) => self.bends.push(Bend::new(*channel, config.clone())),
+                Mapping::Sustain(config) => {
+                    self.sustains.push(Sustain::new(*channel, config.clone()))
+                }
+            }
         }
 
         Ok(())
@@ -319,95 +1012,505 @@ fn generate_note_mapping() -> HashMap<HIDCodes, Key> {
         .collect()
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct PortOption(usize, String, bool);
+pub type KeyMappings = HashMap<HIDCodes, Vec<(Channel, Mapping)>>;
 
-pub struct MidiService {
-    pub port_options: Option<Vec<PortOption>>,
-    connection: Option<MidiOutputConnection>,
-    pub keys: HashMap<HIDCodes, Key>,
-    pub amount_to_shift: i8,
-    pub note_config: NoteConfig,
+// Settings specific to a single device, falling back to the global mapping when there isn't one for the device
+#[derive(Debug, Clone, Default)]
+pub struct DeviceProfile {
+    pub mapping: Option<KeyMappings>,
+    // Semitones added on top of the modifiers for every note on the device
+    pub transpose: i8,
 }
 
-//TODO: Determine if this is safe (LUL imagine saying it may be safe when it literally says unsafe) or a different solution is required
-// Tbf haven't ran into any issues yet, so might be okay
-unsafe impl Send for MidiService {}
-unsafe impl Sync for MidiService {}
+#[derive(Debug)]
+pub struct Device {
+    // None for the merged device
+    pub info: Option<DeviceInfo>,
+    pub keys: HashMap<HIDCodes, Key>,
+    pub transpose: i8,
+}
 
-impl MidiService {
-    pub fn new() -> Self {
-        MidiService {
-            port_options: None,
-            connection: None,
+impl Device {
+    fn new(info: Option<DeviceInfo>) -> Self {
+        Device {
+            info,
             keys: generate_note_mapping(),
-            amount_to_shift: 0,
-            note_config: Default::default(),
+            transpose: 0,
         }
     }
 
-    pub fn update_mapping(
+    fn update_mapping(
         &mut self,
-        mapping: &HashMap<HIDCodes, Vec<(Channel, NoteID)>>,
+        mapping: &KeyMappings,
+        scale: &ScaleConfig,
+        mut sink: Option<&mut dyn NoteSink>,
     ) -> Result<()> {
-        // self.notes =
         let empty_mapping = vec![];
         for (key_id, key) in self.keys.iter_mut() {
-            if let Some(mappings) = mapping.get(&key_id) {
-                key.update_mappings(mappings, &mut self.connection)?;
+            let sink = sink.as_mut().map(|sink| &mut **sink as &mut dyn NoteSink);
+            if let Some(mappings) = mapping.get(key_id) {
+                key.update_mappings(mappings, scale, sink)?;
             } else {
-                key.update_mappings(&empty_mapping, &mut self.connection)?;
+                key.update_mappings(&empty_mapping, scale, sink)?;
             }
         }
         Ok(())
     }
 
-    pub fn set_note_config(&mut self, note_config: NoteConfig) {
-        self.note_config = note_config;
+    fn update_key_configs(&mut self, configs: &HashMap<HIDCodes, KeyConfig>) {
+        for (key_id, key) in self.keys.iter_mut() {
+            key.config = configs.get(key_id).cloned().unwrap_or_default();
+        }
     }
 
-    // pub fn init(&mut self, connection_preference: Option<usize>) -> Result<(), Box<dyn Error>> {
-    pub fn init(&mut self) -> Result<u32> {
-        info!("Starting Wooting Analog SDK!");
-        let init_result: SDKResult<u32> = sdk::initialise();
-        let device_num = match init_result.0 {
-            Ok(device_num) => {
-                info!(
-                    "Analog SDK Successfully initialised with {} devices",
-                    device_num
-                );
-                let devices: Vec<DeviceInfo> = sdk::get_connected_devices_info(DEVICE_BUFFER_MAX)
-                    .0
-                    .unwrap();
-                assert_eq!(device_num, devices.len() as u32);
-                for (i, device) in devices.iter().enumerate() {
-                    println!("Device {} is {:?}", i, device);
-                }
-
-                device_num
+    fn release_all(&mut self, mut sink: Option<&mut dyn NoteSink>) -> Result<()> {
+        for key in self.keys.values_mut() {
+            for note in key.notes.iter_mut() {
+                note.drop(sink.as_mut().map(|sink| &mut **sink as &mut dyn NoteSink))?;
             }
-            Err(e) => Err(e).context("Wooting Analog SDK Failed to initialise")?,
-        };
+            // Otherwise the receiver would be left at the last value of a control that's held
+            for control in key.controls.iter_mut() {
+                control.drop(sink.as_mut().map(|sink| &mut **sink as &mut dyn NoteSink))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_values(
+        &mut self,
+        analog_data: &HashMap<u16, f32>,
+        sink: &mut dyn NoteSink,
+        transpose: &Transpose,
+        note_config: &NoteConfig,
+    ) -> Result<()> {
+        // The device transpose is always in semitones
+        let transpose = transpose.with_semitones(self.transpose);
+        for (key_id, key) in self.keys.iter_mut() {
+            let code = key_id.to_u16().expect("Failed to convert HIDCode to u16");
+            let value = analog_data.get(&code).unwrap_or(&0.0);
+            key.update_value(*value, sink, &transpose, note_config)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum PortState {
+    Available,
+    Connected { routing: OutputRouting },
+}
 
-        let midi_out = MidiOutput::new("Wooting Analog MIDI Output")?;
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PortOption(usize, String, PortState);
 
-        let ports = midi_out.ports();
-        self.port_options = Some(
-            ports
+// Things that happened in the service which the app might want to know about, retrieved through `MidiService::take_events`
+#[derive(Serialize, Debug, Clone)]
+#[serde(tag = "type", content = "value")]
+pub enum ServiceEvent {
+    DeviceConnected(DeviceInfo),
+    DeviceDisconnected(DeviceInfo),
+}
+
+pub struct MidiService {
+    pub port_options: Option<Vec<PortOption>>,
+    // Ports we want to output to, we'll connect to them whenever they're available. When there are none configured the first port is used
+    output_configs: Option<Vec<OutputConfig>>,
+    // Shared with the arpeggiator's thread
+    outputs: Arc<Mutex<Outputs>>,
+    arp_thread: Option<ArpThread>,
+    input: Option<MidiInputConnection<()>>,
+    input_config: Option<InputConfig>,
+    // Messages from the input, which get received on midir's thread
+    input_sender: Sender<Vec<u8>>,
+    input_receiver: Receiver<Vec<u8>>,
+    source: Box<dyn AnalogSource>,
+    // Key state of each connected device
+    pub devices: HashMap<DeviceID, Device>,
+    mapping: KeyMappings,
+    device_profiles: HashMap<DeviceID, DeviceProfile>,
+    key_configs: HashMap<HIDCodes, KeyConfig>,
+    // Events from the source's device callback, which may be called from another thread
+    device_event_sender: Sender<(DeviceEventType, DeviceInfo)>,
+    device_event_receiver: Receiver<(DeviceEventType, DeviceInfo)>,
+    events: Vec<ServiceEvent>,
+    pub modifiers: Vec<Modifier>,
+    pub note_config: NoteConfig,
+    // Last pressure sent for each channel wide pressure target
+    channel_pressure: HashMap<(Channel, PressureMode), f32>,
+    // Range in semitones of the pitch bend on each channel with keys mapped to pitch bend
+    bend_ranges: HashMap<Channel, u8>,
+    // Last pitch bend sent for each channel
+    pitch_bend: HashMap<Channel, f32>,
+    // Last sustain pedal value sent for each channel with keys mapped to sustain
+    sustain: HashMap<Channel, f32>,
+    // Holds back the note offs while the pedal is down, for synths which ignore the sustain pedal
+    software_sustain: bool,
+    // Used by the keys mapped to scale degrees, and by the modifiers when they transpose by scale steps
+    scale: ScaleConfig,
+}
+
+impl MidiService {
+    pub fn new() -> Self {
+        Self::with_source(Box::new(SdkSource::new(DEVICE_BUFFER_MAX)))
+    }
+
+    pub fn with_source(source: Box<dyn AnalogSource>) -> Self {
+        let (device_event_sender, device_event_receiver) = channel();
+        let (input_sender, input_receiver) = channel();
+        MidiService {
+            port_options: None,
+            output_configs: None,
+            outputs: Default::default(),
+            arp_thread: None,
+            input: None,
+            input_config: None,
+            input_sender,
+            input_receiver,
+            source,
+            devices: HashMap::new(),
+            mapping: HashMap::new(),
+            device_profiles: HashMap::new(),
+            key_configs: HashMap::new(),
+            device_event_sender,
+            device_event_receiver,
+            events: vec![],
+            modifiers: vec![],
+            note_config: Default::default(),
+            channel_pressure: HashMap::new(),
+            bend_ranges: HashMap::new(),
+            pitch_bend: HashMap::new(),
+            sustain: HashMap::new(),
+            software_sustain: false,
+            scale: Default::default(),
+        }
+    }
+
+    pub fn update_mapping(&mut self, mapping: &KeyMappings) -> Result<()> {
+        self.mapping = mapping.clone();
+        self.apply_mappings()
+    }
+
+    pub fn update_device_profiles(
+        &mut self,
+        profiles: &HashMap<DeviceID, DeviceProfile>,
+    ) -> Result<()> {
+        self.device_profiles = profiles.clone();
+        self.apply_mappings()
+    }
+
+    fn apply_mappings(&mut self) -> Result<()> {
+        {
+            let mut outputs = self.outputs.lock().unwrap();
+            for (device_id, device) in self.devices.iter_mut() {
+                let profile = self.device_profiles.get(device_id);
+                let mapping = profile
+                    .and_then(|profile| profile.mapping.as_ref())
+                    .unwrap_or(&self.mapping);
+                device.update_mapping(mapping, &self.scale, outputs.as_sink())?;
+                device.transpose = profile.map(|profile| profile.transpose).unwrap_or(0);
+            }
+        }
+
+        self.bend_ranges.clear();
+        let all_mappings = std::iter::once(&self.mapping).chain(
+            self.device_profiles
+                .values()
+                .filter_map(|profile| profile.mapping.as_ref()),
+        );
+        for (channel, mapping) in all_mappings.flat_map(|mapping| mapping.values().flatten()) {
+            if let Mapping::PitchBend(config) = mapping {
+                let range = self.bend_ranges.entry(*channel).or_insert(0);
+                *range = u8::max(*range, config.range);
+            }
+        }
+        self.send_bend_ranges()?;
+        Ok(())
+    }
+
+    fn add_device(&mut self, device_id: DeviceID, info: Option<DeviceInfo>) -> Result<bool> {
+        if self.devices.contains_key(&device_id) {
+            return Ok(false);
+        }
+        let mut device = Device::new(info);
+        let profile = self.device_profiles.get(&device_id);
+        let mapping = profile
+            .and_then(|profile| profile.mapping.as_ref())
+            .unwrap_or(&self.mapping);
+        // The device is new, so there's nothing held which would need a sink to release
+        device.update_mapping(mapping, &self.scale, None)?;
+        device.transpose = profile.map(|profile| profile.transpose).unwrap_or(0);
+        device.update_key_configs(&self.key_configs);
+        self.devices.insert(device_id, device);
+        Ok(true)
+    }
+
+    // Removes the device, releasing any notes that are still held on it
+    fn remove_device(&mut self, device_id: DeviceID) -> Result<Option<Device>> {
+        if let Some(mut device) = self.devices.remove(&device_id) {
+            device.release_all(self.outputs.lock().unwrap().as_sink())?;
+            Ok(Some(device))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn handle_device_events(&mut self) -> Result<()> {
+        while let Ok((event, info)) = self.device_event_receiver.try_recv() {
+            match event {
+                DeviceEventType::Connected => {
+                    info!("Device connected: {:?}", info);
+                    // The merged device is only used while we don't know about any devices
+                    self.remove_device(MERGED_DEVICE)?;
+                    if self.add_device(info.device_id, Some(info.clone()))? {
+                        self.events.push(ServiceEvent::DeviceConnected(info));
+                    }
+                }
+                DeviceEventType::Disconnected => {
+                    info!("Device disconnected: {:?}", info);
+                    if self.remove_device(info.device_id)?.is_some() {
+                        self.events.push(ServiceEvent::DeviceDisconnected(info));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Returns the events which happened since the last call
+    pub fn take_events(&mut self) -> Vec<ServiceEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    // Replaces all outputs with the given sink, any notes still held on the previous outputs will be released first
+    pub fn set_sink(&mut self, sink: Box<dyn NoteSink>) -> Result<()> {
+        self.release_all()?;
+        self.outputs.lock().unwrap().clear();
+        self.pitch_bend.clear();
+        self.add_output(Output::new(None, OutputRouting::All, sink))
+    }
+
+    fn add_output(&mut self, mut output: Output) -> Result<()> {
+        let mpe = self.outputs.lock().unwrap().mpe().cloned();
+        if let Some(mpe) = mpe {
+            for message in mpe.configuration_messages().iter() {
+                output.raw_message(message)?;
+            }
+        }
+        for (channel, range) in self.bend_ranges.iter() {
+            output.pitch_bend_range(*range, *channel)?;
+        }
+        self.outputs.lock().unwrap().push(output);
+        Ok(())
+    }
+
+    // Stops the notes that are on at the output, without touching the note state. Used when the output stops receiving the notes
+    fn release_output(&self, port: &str) -> Result<()> {
+        let notes: Vec<(NoteID, Channel)> = self
+            .keys()
+            .flat_map(|(_, _, key)| key.notes.iter())
+            .filter_map(|note| note.sent_note.map(|sent_note| (sent_note, note.channel)))
+            .collect();
+        self.outputs.lock().unwrap().release_output(port, &notes)
+    }
+
+    fn send_bend_ranges(&mut self) -> Result<()> {
+        let mut outputs = self.outputs.lock().unwrap();
+        if let Some(sink) = outputs.as_sink() {
+            for (channel, range) in self.bend_ranges.iter() {
+                sink.pitch_bend_range(*range, *channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn release_all(&mut self) -> Result<()> {
+        let mut outputs = self.outputs.lock().unwrap();
+        for device in self.devices.values_mut() {
+            device.release_all(outputs.as_sink())?;
+        }
+        // The notes are meant to stop now, even if the pedal is still down
+        outputs.set_sustained_channels(&[])
+    }
+
+    pub fn update_key_configs(&mut self, configs: &HashMap<HIDCodes, KeyConfig>) {
+        self.key_configs = configs.clone();
+        for device in self.devices.values_mut() {
+            device.update_key_configs(configs);
+        }
+    }
+
+    // Iterates over the keys of every device
+    pub fn keys(&self) -> impl Iterator<Item = (&DeviceID, &HIDCodes, &Key)> {
+        self.devices.iter().flat_map(|(device_id, device)| {
+            device
+                .keys
                 .iter()
-                .enumerate()
-                .map(|(i, port)| PortOption(i, midi_out.port_name(&port).unwrap(), i == 0))
-                .collect(),
+                .map(move |(key_id, key)| (device_id, key_id, key))
+        })
+    }
+
+    // Modifiers on the same key and mode as before keep their state, so changing the other settings doesn't turn off a toggled shift
+    pub fn set_modifiers(&mut self, modifiers: &[ModifierConfig]) {
+        let mut previous = std::mem::take(&mut self.modifiers);
+        self.modifiers = modifiers
+            .iter()
+            .cloned()
+            .map(|config| {
+                let index = previous.iter().position(|modifier| {
+                    modifier.config.key == config.key && modifier.config.mode == config.mode
+                });
+                match index {
+                    Some(index) => {
+                        let mut modifier = previous.remove(index);
+                        modifier.config = config;
+                        modifier
+                    }
+                    None => Modifier::new(config),
+                }
+            })
+            .collect();
+    }
+
+    // Combined shift of all the active modifiers
+    pub fn shift_amount(&self) -> i8 {
+        let shift: i16 = self
+            .modifiers
+            .iter()
+            .filter(|modifier| modifier.active)
+            .map(|modifier| modifier.config.shift as i16)
+            .sum();
+        shift.clamp(i8::MIN as i16, i8::MAX as i16) as i8
+    }
+
+    // Turns MPE on/off, the held notes get released first as they'd move to different channels
+    pub fn set_mpe(&mut self, config: &MpeConfig) -> Result<()> {
+        let current = self
+            .outputs
+            .lock()
+            .unwrap()
+            .mpe()
+            .cloned()
+            .unwrap_or_default();
+        if current == *config || (!current.enabled && !config.enabled) {
+            return Ok(());
+        }
+        self.release_all()?;
+        let mut outputs = self.outputs.lock().unwrap();
+        outputs.set_mpe(config);
+        if let Some(sink) = outputs.as_sink() {
+            let mut messages = vec![];
+            if current.enabled {
+                // Configuring the zone without any member channels turns it off
+                let disabled = MpeConfig {
+                    enabled: false,
+                    ..current
+                };
+                messages.extend(disabled.configuration_messages());
+            }
+            if config.enabled {
+                messages.extend(config.configuration_messages());
+            }
+            for message in messages.iter() {
+                sink.raw_message(message)?;
+            }
+        }
+        drop(outputs);
+        // Channel wide state is tracked per channel, which has now changed
+        self.channel_pressure.clear();
+        self.pitch_bend.clear();
+        self.send_bend_ranges()
+    }
+
+    // Turns the arpeggiator on/off or changes its settings. It runs on its own thread so the step timing doesn't depend on `poll`
+    pub fn set_arp(&mut self, config: &ArpConfig) -> Result<()> {
+        let current = self.outputs.lock().unwrap().arp().cloned();
+        if current.as_ref() == Some(config) || (current.is_none() && !config.enabled) {
+            return Ok(());
+        }
+        if current.is_some() != config.enabled {
+            // Notes that are held were either sent straight out or are held by the arpeggiator, which won't be the case after switching
+            self.release_all()?;
+        }
+        self.outputs.lock().unwrap().set_arp(config);
+        if !config.enabled {
+            self.arp_thread = None;
+        } else if self.arp_thread.is_none() {
+            self.arp_thread = Some(ArpThread::start(self.outputs.clone()));
+        }
+        Ok(())
+    }
+
+    pub fn set_software_sustain(&mut self, enabled: bool) -> Result<()> {
+        self.software_sustain = enabled;
+        if !enabled {
+            self.outputs.lock().unwrap().set_sustained_channels(&[])?;
+        }
+        Ok(())
+    }
+
+    // Takes effect on the next poll, held notes follow their transpose policy
+    pub fn set_scale(&mut self, scale: ScaleConfig) {
+        self.scale = scale;
+    }
+
+    pub fn scale(&self) -> &ScaleConfig {
+        &self.scale
+    }
+
+    pub fn set_note_config(&mut self, note_config: NoteConfig) {
+        self.note_config = note_config;
+    }
+
+    // pub fn init(&mut self, connection_preference: Option<usize>) -> Result<(), Box<dyn Error>> {
+    pub fn init(&mut self) -> Result<u32> {
+        info!("Starting Wooting Analog SDK!");
+        let device_num = self
+            .source
+            .initialise()
+            .context("Wooting Analog SDK Failed to initialise")?;
+        info!(
+            "Analog SDK Successfully initialised with {} devices",
+            device_num
         );
-        info!("We have {} ports available!", ports.len());
-        if ports.len() > 0 {
-            info!("Opening connection");
-            self.connection = Some(
-                midi_out
-                    .connect(&ports[0], "wooting-analog-midi")
-                    .map_err(|e| anyhow!("Error: {}", e))?,
+        let devices: Vec<DeviceInfo> = self.source.connected_devices()?;
+        // The count is out of date if a device got plugged in or out in between
+        if device_num != devices.len() as u32 {
+            warn!(
+                "SDK reported {} devices, but {} are connected",
+                device_num,
+                devices.len()
             );
-        } else {
+        }
+        for (i, device) in devices.iter().enumerate() {
+            info!("Device {} is {:?}", i, device);
+            self.add_device(device.device_id, Some(device.clone()))?;
+        }
+
+        let sender = self.device_event_sender.clone();
+        if let Err(e) = self
+            .source
+            .set_device_event_callback(Box::new(move |event, info| {
+                if let Err(e) = sender.send((event, info)) {
+                    error!("Failed to forward device event: {}", e);
+                }
+            }))
+        {
+            warn!("Failed to subscribe to device events: {}", e);
+        }
+
+        if !self.outputs.lock().unwrap().is_empty() {
+            info!("Output sink already provided, not opening a port");
+            return Ok(device_num);
+        }
+
+        self.refresh_ports()?;
+        let port_count = self
+            .port_options
+            .as_ref()
+            .map_or(0, |options| options.len());
+        info!("We have {} ports available!", port_count);
+        if self.outputs.lock().unwrap().is_empty() {
             info!("No output ports available!");
         }
         // self.port_options = Some(midi_out);
@@ -415,87 +1518,411 @@ impl MidiService {
     }
 
     pub fn get_connected_devices(&self) -> Result<Vec<DeviceInfo>> {
-        return Ok(sdk::get_connected_devices_info(DEVICE_BUFFER_MAX).0?);
+        self.source.connected_devices()
     }
 
-    pub fn select_port(&mut self, option: usize) -> Result<()> {
-        //TODO: Deal with the case where the port list has changed since the `port_options` was generated
-        if let Some(options) = &self.port_options {
-            if option >= options.len() {
-                bail!("Port option out of range!");
-            }
+    pub fn set_output_configs(&mut self, configs: Option<Vec<OutputConfig>>) {
+        self.output_configs = configs;
+    }
+
+    // The configured outputs, or the ports we're currently outputting to if none have been configured yet
+    pub fn output_configs(&self) -> Vec<OutputConfig> {
+        self.output_configs.clone().unwrap_or_else(|| {
+            self.outputs
+                .lock()
+                .unwrap()
+                .iter()
+                .filter_map(|output| {
+                    output.port().map(|port| OutputConfig {
+                        port: port.clone(),
+                        routing: output.routing().clone(),
+                    })
+                })
+                .collect()
+        })
+    }
 
-            let selection = &options[option];
+    // Adds the port as an output, or updates its routing if it already is one
+    pub fn add_output_port(&mut self, config: OutputConfig) -> Result<()> {
+        // Connect before it's saved, so a port which can't be opened doesn't end up in the configs
+        let connected = self.outputs.lock().unwrap().get(&config.port).is_some();
+        if !connected {
+            self.connect_port(&config)?;
+        }
+        let mut configs = self.output_configs();
+        if let Some(existing) = configs
+            .iter_mut()
+            .find(|existing| existing.port == config.port)
+        {
+            existing.routing = config.routing;
+        } else {
+            configs.push(config);
+        }
+        self.output_configs = Some(configs);
+        self.refresh_ports()?;
+        Ok(())
+    }
+
+    pub fn remove_output_port(&mut self, port: &str) -> Result<()> {
+        let mut configs = self.output_configs();
+        configs.retain(|config| config.port != port);
+        self.output_configs = Some(configs);
+        self.refresh_ports()?;
+        Ok(())
+    }
+
+    fn connect_port(&mut self, config: &OutputConfig) -> Result<()> {
+        let midi_out = output::midi_output()?;
+        let port = output::output_ports(&midi_out)
+            .into_iter()
+            .find(|(_, name)| name == &config.port)
+            .map(|(port, _)| port)
+            .ok_or_else(|| anyhow!("Port '{}' is not available", config.port))?;
+        info!("Opening connection to '{}'", config.port);
+        let connection = output::connect_output(midi_out, port.as_ref())?;
+        self.add_output(Output::new(
+            Some(config.port.clone()),
+            config.routing.clone(),
+            Box::new(connection),
+        ))
+    }
 
-            // Port is already the selected one, don't need to do anything
-            if selection.2 {
-                return Ok(());
+    // Stops outputting to the port, releasing the notes that are on
+    fn disconnect_port(&mut self, port: &str) -> Result<()> {
+        if self.outputs.lock().unwrap().get(port).is_some() {
+            info!("Closing connection to '{}'", port);
+            self.release_output(port)?;
+            self.outputs.lock().unwrap().remove(port);
+        }
+        Ok(())
+    }
+
+    fn update_port_options(&mut self, ports: &[(Option<MidiOutputPort>, String)]) {
+        let outputs = self.outputs.lock().unwrap();
+        self.port_options = Some(
+            ports
+                .iter()
+                .enumerate()
+                .map(|(i, (_, name))| {
+                    let state = match outputs.get(name) {
+                        Some(output) => PortState::Connected {
+                            routing: output.routing().clone(),
+                        },
+                        None => PortState::Available,
+                    };
+                    PortOption(i, name.clone(), state)
+                })
+                .collect(),
+        );
+    }
+
+    // Re-enumerates the output ports and connects/disconnects outputs to match the configured ones. Returns whether the port options changed
+    pub fn refresh_ports(&mut self) -> Result<bool> {
+        let previous = self.port_options.clone();
+        let midi_out = output::midi_output()?;
+        let ports = output::output_ports(&midi_out);
+        let is_available = |name: &String| ports.iter().any(|(_, port_name)| port_name == name);
+
+        let connected: Vec<String> = self
+            .outputs
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|output| output.port().cloned())
+            .collect();
+        for port in connected.iter() {
+            if !is_available(port) {
+                warn!("Output port '{}' has disappeared", port);
+                // The port is gone, so there's nothing to release the notes on
+                self.outputs.lock().unwrap().remove(port);
             }
+        }
 
-            let midi_out = MidiOutput::new("Wooting Analog MIDI Output")?;
-            let ports = midi_out.ports();
-            self.port_options = Some(
-                ports
-                    .iter()
-                    .enumerate()
-                    .map(|(i, port)| PortOption(i, midi_out.port_name(&port).unwrap(), i == option))
-                    .collect(),
-            );
+        if let Some(configs) = self.output_configs.clone() {
+            for port in connected.iter() {
+                if !configs.iter().any(|config| &config.port == port) {
+                    self.disconnect_port(port)?;
+                }
+            }
+            for config in configs.iter().filter(|config| is_available(&config.port)) {
+                let routing = self
+                    .outputs
+                    .lock()
+                    .unwrap()
+                    .get(&config.port)
+                    .map(|output| output.routing().clone());
+                match routing {
+                    Some(routing) if routing == config.routing => {}
+                    Some(_) => {
+                        // Release everything before the routing changes, so nothing gets stuck on channels which aren't routed anymore
+                        self.release_output(&config.port)?;
+                        self.outputs
+                            .lock()
+                            .unwrap()
+                            .set_routing(&config.port, config.routing.clone());
+                    }
+                    None => {
+                        // One port failing to open (e.g. it's held by another app) shouldn't stop the others
+                        if let Err(e) = self.connect_port(config) {
+                            error!("Failed to connect to '{}': {}", config.port, e);
+                        }
+                    }
+                }
+            }
+        } else if self.outputs.lock().unwrap().is_empty() {
+            if let Some((_, name)) = ports.first() {
+                if let Err(e) = self.connect_port(&OutputConfig {
+                    port: name.clone(),
+                    routing: OutputRouting::All,
+                }) {
+                    error!("Failed to connect to '{}': {}", name, e);
+                }
+            }
+        }
 
-            self.connection = Some(
-                midi_out
-                    .connect(&ports[option], "wooting-analog-midi")
-                    .map_err(|e| anyhow!("Error: {}", e))?,
-            );
+        self.update_port_options(&ports);
+        Ok(self.port_options != previous)
+    }
 
-            Ok(())
-        } else {
-            return Err(anyhow!("Port options not initialised"));
+    // Connects to the given input, replacing the current one. `None` disconnects the input
+    pub fn set_input(&mut self, config: Option<InputConfig>) -> Result<()> {
+        // Dropping the connection closes it
+        self.input = None;
+        self.input_config = None;
+        if let Some(config) = config {
+            info!("Connecting to input '{}'", config.port);
+            self.input = Some(input::connect_input(&config, self.input_sender.clone())?);
+            self.input_config = Some(config);
+        }
+        Ok(())
+    }
+
+    pub fn input_config(&self) -> Option<&InputConfig> {
+        self.input_config.as_ref()
+    }
+
+    pub fn get_input_options(&self) -> Result<Vec<String>> {
+        input::input_port_names()
+    }
+
+    fn merge_input(&mut self) -> Result<()> {
+        let mut outputs = self.outputs.lock().unwrap();
+        while let Ok(message) = self.input_receiver.try_recv() {
+            if let Some(sink) = outputs.as_sink() {
+                sink.raw_message(&message)?;
+            }
         }
+        Ok(())
     }
 
     pub fn poll(&mut self) -> Result<()> {
-        if self.connection.is_none() {
+        self.merge_input()?;
+
+        // Devices still get tracked without a connection, so the UI knows about them before an output is picked
+        self.handle_device_events()?;
+
+        if self.outputs.lock().unwrap().is_empty() {
             bail!("No MIDI connection!");
         }
 
-        let read_result: SDKResult<HashMap<u16, f32>> =
-            sdk::read_full_buffer(ANALOG_BUFFER_READ_MAX);
-        match read_result.0 {
-            Ok(analog_data) => {
-                let modifier_pressed = (*analog_data
-                    .get(&MODIFIER_KEY.to_u16().unwrap())
-                    .unwrap_or(&0.0))
-                    >= ACTUATION_POINT;
-                for (key_id, key) in self.keys.iter_mut() {
-                    let code = key_id.to_u16().expect("Failed to convert HIDCode to u16");
-                    let value = analog_data.get(&code).unwrap_or(&0.0);
-                    key.update_value(
-                        *value,
-                        self.connection.as_mut().unwrap(),
-                        if modifier_pressed {
-                            self.amount_to_shift
-                        } else {
-                            0
-                        },
+        let mut frames: Vec<(DeviceID, HashMap<u16, f32>)> = vec![];
+        if self.devices.is_empty() || self.devices.contains_key(&MERGED_DEVICE) {
+            // We don't know about any devices, so everything gets read as one
+            let analog_data = self
+                .source
+                .read_full_buffer(ANALOG_BUFFER_READ_MAX)
+                .context("Failed to read buffer")?;
+            self.add_device(MERGED_DEVICE, None)?;
+            frames.push((MERGED_DEVICE, analog_data));
+        } else {
+            let mut disconnected = vec![];
+            for device_id in self.devices.keys() {
+                match self
+                    .source
+                    .read_device_buffer(ANALOG_BUFFER_READ_MAX, *device_id)
+                {
+                    Ok(analog_data) => frames.push((*device_id, analog_data)),
+                    Err(e) => match e.root_cause().downcast_ref::<WootingAnalogResult>() {
+                        Some(WootingAnalogResult::NoDevices)
+                        | Some(WootingAnalogResult::DeviceDisconnected) => {
+                            warn!("Device {} has been disconnected", device_id);
+                            disconnected.push(*device_id);
+                        }
+                        // The device is still there, so it gets read again next poll
+                        _ => warn!("Failed to read buffer of device {}: {}", device_id, e),
+                    },
+                }
+            }
+            // The disconnected event may not have come through yet, so treat these as disconnected now
+            for device_id in disconnected {
+                if let Some(info) = self
+                    .remove_device(device_id)?
+                    .and_then(|device| device.info)
+                {
+                    self.events.push(ServiceEvent::DeviceDisconnected(info));
+                }
+            }
+        }
+
+        // Modifiers apply across all devices
+        for modifier in self.modifiers.iter_mut() {
+            let value = frames
+                .iter()
+                .filter_map(|(_, analog_data)| analog_data.get(&(modifier.config.key as u16)))
+                .fold(0.0, |max: f32, value| max.max(*value));
+            modifier.update_value(value);
+        }
+        let transpose = Transpose::new(self.shift_amount(), &self.scale);
+        {
+            let mut outputs = self.outputs.lock().unwrap();
+            for (device_id, analog_data) in frames.iter() {
+                if let Some(device) = self.devices.get_mut(device_id) {
+                    device.update_values(
+                        analog_data,
+                        outputs.as_sink().unwrap(),
+                        &transpose,
                         &self.note_config,
                     )?;
                 }
             }
-            Err(e) => {
-                Err(e).context("Failed to read buffer")?;
+            // The arpeggiator's gate length follows the depth of the keys holding the notes
+            if outputs.arp().is_some() {
+                for (_, _, key) in self.keys() {
+                    for note in key.notes.iter() {
+                        if let Some(sent_note) = note.sent_note {
+                            outputs.set_arp_depth(sent_note, note.channel, key.current_value);
+                        }
+                    }
+                }
             }
-        };
+        }
+        self.update_channel_pressure()?;
+        self.update_pitch_bend()?;
+        self.update_sustain()?;
+        Ok(())
+    }
+
+    fn update_sustain(&mut self) -> Result<()> {
+        let mut pedals: HashMap<Channel, f32> = HashMap::new();
+        let mut down: Vec<Channel> = vec![];
+        for sustain in self.keys().flat_map(|(_, _, key)| key.sustains.iter()) {
+            let value = pedals.entry(sustain.channel).or_insert(0.0);
+            *value = f32::max(*value, sustain.value);
+            if sustain.down && !down.contains(&sustain.channel) {
+                down.push(sustain.channel);
+            }
+        }
+
+        let previous = &self.sustain;
+        let mut outputs = self.outputs.lock().unwrap();
+        // Channels which no longer have any keys mapped to sustain get their pedal lifted
+        let released = previous
+            .keys()
+            .filter(|channel| !pedals.contains_key(channel))
+            .map(|channel| (channel, 0.0));
+        let changed = pedals
+            .iter()
+            .filter(|(channel, value)| previous.get(channel) != Some(value))
+            .map(|(channel, value)| (channel, *value));
+        for (channel, value) in released.chain(changed) {
+            outputs.control_change(SUSTAIN_CC, value, *channel)?;
+        }
+        if self.software_sustain {
+            outputs.set_sustained_channels(&down)?;
+        }
+
+        drop(outputs);
+        self.sustain = pedals;
+        Ok(())
+    }
+
+    fn update_pitch_bend(&mut self) -> Result<()> {
+        let mut bends: HashMap<Channel, f32> = HashMap::new();
+        for bend in self.keys().flat_map(|(_, _, key)| key.bends.iter()) {
+            *bends.entry(bend.channel).or_insert(0.0) += bend.semitones();
+        }
+        for (channel, bend) in bends.iter_mut() {
+            let range = *self.bend_ranges.get(channel).unwrap_or(&0) as f32;
+            *bend = if range > 0.0 {
+                (*bend / range).clamp(-1.0, 1.0)
+            } else {
+                0.0
+            };
+        }
+
+        let previous = &self.pitch_bend;
+        let mut outputs = self.outputs.lock().unwrap();
+        let sink = outputs.as_sink().unwrap();
+        // Channels which no longer have any keys mapped to pitch bend get returned to the center
+        let released = previous
+            .keys()
+            .filter(|channel| !bends.contains_key(channel))
+            .map(|channel| (channel, 0.0));
+        let changed = bends
+            .iter()
+            .filter(|(channel, bend)| previous.get(channel) != Some(bend))
+            .map(|(channel, bend)| (channel, *bend));
+        for (channel, bend) in released.chain(changed) {
+            sink.pitch_bend(bend, *channel)?;
+        }
+
+        drop(outputs);
+        self.pitch_bend = bends;
+        Ok(())
+    }
+
+    fn update_channel_pressure(&mut self) -> Result<()> {
+        let mut pressures: HashMap<(Channel, PressureMode), f32> = HashMap::new();
+        for note in self.keys().flat_map(|(_, _, key)| key.notes.iter()) {
+            match note.pressure_mode {
+                PressureMode::ChannelPressure | PressureMode::ControlChange { .. }
+                    if note.sent_note.is_some() =>
+                {
+                    let pressure = pressures
+                        .entry((note.channel, note.pressure_mode))
+                        .or_insert(0.0);
+                    *pressure = f32::max(*pressure, note.pressure);
+                }
+                _ => {}
+            }
+        }
+
+        let previous = &self.channel_pressure;
+        let mut outputs = self.outputs.lock().unwrap();
+        let sink = outputs.as_sink().unwrap();
+        // Targets which no longer have any notes held get reset
+        let released = previous
+            .keys()
+            .filter(|target| !pressures.contains_key(target))
+            .map(|target| (target, 0.0));
+        let changed = pressures
+            .iter()
+            .filter(|(target, pressure)| previous.get(target) != Some(pressure))
+            .map(|(target, pressure)| (target, *pressure));
+        for ((channel, mode), pressure) in released.chain(changed) {
+            match mode {
+                PressureMode::ChannelPressure => sink.channel_pressure(pressure, *channel)?,
+                PressureMode::ControlChange { control } => {
+                    sink.control_change(*control, pressure, *channel)?
+                }
+                _ => {}
+            }
+        }
+
+        drop(outputs);
+        self.channel_pressure = pressures;
         Ok(())
     }
 
     pub fn uninit(&mut self) {
         info!("Uninitialising MidiService");
-        sdk::uninitialise();
+        self.source.clear_device_event_callback();
+        self.source.uninitialise();
         trace!("Sdk uninit done");
-        if let Some(output) = self.connection.take() {
-            output.close();
-        }
+        self.input = None;
+        // Stops the arpeggiator's thread before the outputs go away
+        self.arp_thread = None;
+        self.outputs.lock().unwrap().clear();
         trace!("MidiService uninit complete");
     }
 }
@@ -504,3 +1931,639 @@ impl Drop for MidiService {
         self.uninit();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sent(sink: &RecordingSink) -> Vec<SinkMessage> {
+        sink.take_messages()
+            .into_iter()
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    // Fixed curves, so the velocities don't depend on how fast the test runs
+    fn fixed_velocity_config() -> NoteConfig {
+        NoteConfig::default()
+            .with_velocity_curve(VelocityCurve {
+                curve: Curve::Fixed { value: 0.8 },
+                ..Default::default()
+            })
+            .with_release_velocity(
+                default_velocity_scale(),
+                VelocityCurve {
+                    curve: Curve::Fixed { value: 0.3 },
+                    ..Default::default()
+                },
+            )
+    }
+
+    // Moves the key through the depths one update at a time, the first depth is where it starts
+    fn play(
+        note: &mut Note,
+        depths: &[f32],
+        sink: &mut RecordingSink,
+        transpose: &Transpose,
+        note_config: &NoteConfig,
+    ) {
+        for depths in depths.windows(2) {
+            note.update_current_value(depths[0], depths[1], sink, transpose, note_config)
+                .unwrap();
+        }
+    }
+
+    fn device(device_id: DeviceID) -> DeviceInfo {
+        DeviceInfo {
+            vendor_id: 0x31e3,
+            product_id: 0x1100,
+            manufacturer_name: "Wooting".to_string(),
+            device_name: format!("Test device {}", device_id),
+            device_id,
+            device_type: sdk::DeviceType::Keyboard,
+        }
+    }
+
+    fn frame(keys: &[(HIDCodes, f32)]) -> HashMap<u16, f32> {
+        keys.iter()
+            .map(|(code, value)| (*code as u16, *value))
+            .collect()
+    }
+
+    // Service playing A as note 60 on device 1, outputting to a port named "Synth" which records what it's sent
+    fn port_service(source: &ScriptedSource, sink: &RecordingSink) -> MidiService {
+        source.set_devices(vec![device(1)]);
+        let mut service = MidiService::with_source(Box::new(source.clone()));
+        service
+            .add_output(Output::new(
+                Some("Synth".to_string()),
+                OutputRouting::All,
+                Box::new(sink.clone()),
+            ))
+            .unwrap();
+        service.set_note_config(fixed_velocity_config());
+        let mut mapping = KeyMappings::new();
+        mapping.insert(HIDCodes::A, vec![(0, Mapping::Note { note: 60 })]);
+        service.update_mapping(&mapping).unwrap();
+        service.init().unwrap();
+        service
+    }
+
+    #[test]
+    fn press_aftertouch_release() {
+        let mut sink = RecordingSink::new();
+        let scale = ScaleConfig::default();
+        let transpose = Transpose::new(0, &scale);
+        let mut note = Note::new(0, 60);
+        play(
+            &mut note,
+            &[0.0, 0.6, 0.8, 0.8, 0.0],
+            &mut sink,
+            &transpose,
+            &fixed_velocity_config(),
+        );
+        assert_eq!(
+            sent(&sink),
+            vec![
+                SinkMessage::NoteOn {
+                    note_id: 60,
+                    velocity: 0.8,
+                    channel: 0
+                },
+                SinkMessage::PolyphonicAftertouch {
+                    note_id: 60,
+                    pressure: 0.8,
+                    channel: 0
+                },
+                SinkMessage::NoteOff {
+                    note_id: 60,
+                    velocity: 0.3,
+                    channel: 0
+                },
+            ]
+        );
+    }
+
+    // Plays the depths on note 60 with rapid trigger on, returning whether each message was a note on
+    fn play_rapid_trigger(depths: &[f32]) -> Vec<bool> {
+        let mut sink = RecordingSink::new();
+        let scale = ScaleConfig::default();
+        let transpose = Transpose::new(0, &scale);
+        let note_config = fixed_velocity_config()
+            .with_pressure(PressureMode::Off, false)
+            .with_rapid_trigger(RapidTrigger {
+                enabled: true,
+                release_distance: 0.1,
+                press_distance: 0.1,
+            });
+        let mut note = Note::new(0, 60);
+        play(&mut note, depths, &mut sink, &transpose, &note_config);
+        sent(&sink)
+            .into_iter()
+            .map(|message| match message {
+                SinkMessage::NoteOn { note_id: 60, .. } => true,
+                SinkMessage::NoteOff { note_id: 60, .. } => false,
+                message => panic!("Unexpected message {:?}", message),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn rapid_trigger_follows_direction() {
+        // On at the threshold, off once it's come up 0.1 from the deepest point, on again once it's gone 0.1 down from the shallowest point
+        assert_eq!(
+            play_rapid_trigger(&[0.0, 0.6, 0.8, 0.75, 0.65, 0.6, 0.65, 0.75, 0.0]),
+            vec![true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_starts_at_threshold() {
+        // Moving around below the threshold doesn't trigger until the key has been pressed past it, after which even going back down from below the threshold triggers. Fully releasing the key brings the threshold back
+        assert_eq!(
+            play_rapid_trigger(&[0.0, 0.3, 0.2, 0.35, 0.9, 0.3, 0.45, 0.0, 0.3]),
+            vec![true, false, true, false]
+        );
+    }
+
+    #[test]
+    fn recording_sink_clones_share_messages() {
+        let sink = RecordingSink::new();
+        let mut clone = sink.clone();
+        clone.control_change(1, 0.5, 2).unwrap();
+        clone.pitch_bend(-1.0, 2).unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![
+                SinkMessage::ControlChange {
+                    control: 1,
+                    value: 0.5,
+                    channel: 2
+                },
+                SinkMessage::PitchBend {
+                    value: -1.0,
+                    channel: 2
+                },
+            ]
+        );
+        assert!(clone.messages().is_empty());
+    }
+
+    fn note_on(note_id: NoteID, velocity: f32) -> SinkMessage {
+        SinkMessage::NoteOn {
+            note_id,
+            velocity,
+            channel: 0,
+        }
+    }
+
+    fn note_off(note_id: NoteID, velocity: f32) -> SinkMessage {
+        SinkMessage::NoteOff {
+            note_id,
+            velocity,
+            channel: 0,
+        }
+    }
+
+    #[test]
+    fn latch_keeps_pitch_until_released() {
+        let mut sink = RecordingSink::new();
+        let scale = ScaleConfig::default();
+        let config = fixed_velocity_config().with_transpose_policy(TransposePolicy::Latch);
+        let mut note = Note::new(0, 60);
+        play(
+            &mut note,
+            &[0.0, 0.6],
+            &mut sink,
+            &Transpose::new(0, &scale),
+            &config,
+        );
+        assert_eq!(sent(&sink), vec![note_on(60, 0.8)]);
+
+        // The shift changes while the note is held
+        let shifted = Transpose::new(12, &scale);
+        play(&mut note, &[0.6, 0.6], &mut sink, &shifted, &config);
+        assert_eq!(sent(&sink), vec![]);
+
+        // The note off goes to the note that was sent rather than the shifted pitch
+        play(&mut note, &[0.6, 0.0], &mut sink, &shifted, &config);
+        assert_eq!(sent(&sink), vec![note_off(60, 0.3)]);
+
+        play(&mut note, &[0.0, 0.6], &mut sink, &shifted, &config);
+        assert_eq!(sent(&sink), vec![note_on(72, 0.8)]);
+    }
+
+    #[test]
+    fn retrigger_moves_held_note() {
+        let mut sink = RecordingSink::new();
+        let scale = ScaleConfig::default();
+        let config = fixed_velocity_config().with_transpose_policy(TransposePolicy::Retrigger);
+        let mut note = Note::new(0, 60);
+        play(
+            &mut note,
+            &[0.0, 0.6],
+            &mut sink,
+            &Transpose::new(0, &scale),
+            &config,
+        );
+        assert_eq!(sent(&sink), vec![note_on(60, 0.8)]);
+
+        let shifted = Transpose::new(12, &scale);
+        play(&mut note, &[0.6, 0.6], &mut sink, &shifted, &config);
+        assert_eq!(
+            sent(&sink),
+            vec![note_off(60, DEFAULT_RELEASE_VELOCITY), note_on(72, 0.8)]
+        );
+
+        play(&mut note, &[0.6, 0.0], &mut sink, &shifted, &config);
+        assert_eq!(sent(&sink), vec![note_off(72, 0.3)]);
+    }
+
+    #[test]
+    fn shift_out_of_range_sends_no_note_on() {
+        let scale = ScaleConfig::default();
+        let shifted = Transpose::new(12, &scale);
+        for policy in [TransposePolicy::Latch, TransposePolicy::Retrigger].iter() {
+            let mut sink = RecordingSink::new();
+            let config = fixed_velocity_config().with_transpose_policy(*policy);
+            let mut note = Note::new(0, 100);
+            play(&mut note, &[0.0, 0.6, 0.0], &mut sink, &shifted, &config);
+            assert_eq!(sent(&sink), vec![]);
+        }
+
+        // A held note which gets shifted out of range is stopped without playing anything in its place
+        let mut sink = RecordingSink::new();
+        let config = fixed_velocity_config().with_transpose_policy(TransposePolicy::Retrigger);
+        let mut note = Note::new(0, 100);
+        play(
+            &mut note,
+            &[0.0, 0.6],
+            &mut sink,
+            &Transpose::new(0, &scale),
+            &config,
+        );
+        play(&mut note, &[0.6, 0.6, 0.0], &mut sink, &shifted, &config);
+        assert_eq!(
+            sent(&sink),
+            vec![note_on(100, 0.8), note_off(100, DEFAULT_RELEASE_VELOCITY)]
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_own_virtual_port_is_hidden() {
+        assert!(output::is_virtual_port("Wooting Analog MIDI"));
+        assert!(output::is_virtual_port(
+            "Wooting Analog MIDI Output:Wooting Analog MIDI 128:0"
+        ));
+        assert!(!output::is_virtual_port("loopMIDI Wooting Analog MIDI"));
+        assert!(!output::is_virtual_port(
+            "Other Client:Wooting Analog MIDI 129:0"
+        ));
+    }
+
+    #[test]
+    fn extreme_degrees_are_out_of_range() {
+        let scale = ScaleConfig {
+            scale: Scale::Custom { intervals: vec![0] },
+            transpose_mode: TransposeMode::ScaleSteps,
+            ..Default::default()
+        };
+        assert_eq!(scale.note(i16::MAX), None);
+        assert_eq!(scale.note(i16::MIN), None);
+        assert_eq!(scale.note(1), Some(72));
+        let transpose = Transpose::new(i8::MAX, &scale);
+        assert_eq!(transpose.apply_degree(i16::MAX), None);
+    }
+
+    #[test]
+    fn remapped_control_returns_to_rest() {
+        let mut sink = RecordingSink::new();
+        let scale = ScaleConfig::default();
+        let transpose = Transpose::new(0, &scale);
+        let mut key = Key::new();
+        let mapping = [(0, Mapping::Control(ControlConfig::new(1)))];
+        key.update_mappings(&mapping, &scale, None).unwrap();
+        key.update_value(1.0, &mut sink, &transpose, &NoteConfig::default())
+            .unwrap();
+        key.update_mappings(&[], &scale, Some(&mut sink)).unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![
+                SinkMessage::ControlChange {
+                    control: 1,
+                    value: 1.0,
+                    channel: 0
+                },
+                SinkMessage::ControlChange {
+                    control: 1,
+                    value: 0.0,
+                    channel: 0
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn unplugged_control_returns_to_rest() {
+        let source = ScriptedSource::new();
+        source.set_devices(vec![device(1), device(2)]);
+        let sink = RecordingSink::new();
+        let mut service = MidiService::with_source(Box::new(source.clone()));
+        service.set_sink(Box::new(sink.clone())).unwrap();
+        let mut mapping = KeyMappings::new();
+        mapping.insert(
+            HIDCodes::A,
+            vec![(0, Mapping::Control(ControlConfig::new(1)))],
+        );
+        service.update_mapping(&mapping).unwrap();
+        service.init().unwrap();
+
+        source.push_device_frame(2, frame(&[(HIDCodes::A, 1.0)]));
+        service.poll().unwrap();
+        // Device 1's key also sends its value the first time it's read
+        assert!(sent(&sink).contains(&SinkMessage::ControlChange {
+            control: 1,
+            value: 1.0,
+            channel: 0
+        }));
+
+        source.set_devices(vec![device(1)]);
+        service.poll().unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::ControlChange {
+                control: 1,
+                value: 0.0,
+                channel: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn scripted_source_through_service() {
+        let source = ScriptedSource::new();
+        source.set_devices(vec![device(1)]);
+        let sink = RecordingSink::new();
+        let mut service = MidiService::with_source(Box::new(source.clone()));
+        service.set_sink(Box::new(sink.clone())).unwrap();
+        service.set_note_config(fixed_velocity_config());
+        let mut mapping = KeyMappings::new();
+        mapping.insert(HIDCodes::A, vec![(0, Mapping::Note { note: 60 })]);
+        service.update_mapping(&mapping).unwrap();
+        assert_eq!(service.init().unwrap(), 1);
+
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        source.push_device_frame(1, frame(&[]));
+        service.poll().unwrap();
+        service.poll().unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![
+                SinkMessage::NoteOn {
+                    note_id: 60,
+                    velocity: 0.8,
+                    channel: 0
+                },
+                SinkMessage::NoteOff {
+                    note_id: 60,
+                    velocity: 0.3,
+                    channel: 0
+                },
+            ]
+        );
+
+        // Plugging in a second keyboard and playing on it
+        source.set_devices(vec![device(1), device(2)]);
+        source.push_device_frame(2, frame(&[(HIDCodes::A, 0.8)]));
+        service.poll().unwrap();
+        assert!(matches!(
+            service.take_events().as_slice(),
+            [ServiceEvent::DeviceConnected(info)] if info.device_id == 2
+        ));
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOn {
+                note_id: 60,
+                velocity: 0.8,
+                channel: 0
+            }]
+        );
+
+        // Unplugging it releases the note it was holding
+        source.set_devices(vec![device(1)]);
+        service.poll().unwrap();
+        assert!(matches!(
+            service.take_events().as_slice(),
+            [ServiceEvent::DeviceDisconnected(info)] if info.device_id == 2
+        ));
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOff {
+                note_id: 60,
+                velocity: DEFAULT_RELEASE_VELOCITY,
+                channel: 0
+            }]
+        );
+        assert!(service.devices.contains_key(&1));
+        assert!(!service.devices.contains_key(&2));
+    }
+
+    #[test]
+    fn failed_read_keeps_device() {
+        let source = ScriptedSource::new();
+        let sink = RecordingSink::new();
+        let mut service = port_service(&source, &sink);
+        source.fail_next_read(1);
+        service.poll().unwrap();
+        assert!(service.devices.contains_key(&1));
+
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        service.poll().unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOn {
+                note_id: 60,
+                velocity: 0.8,
+                channel: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn device_events_without_connection() {
+        let source = ScriptedSource::new();
+        source.set_devices(vec![device(1)]);
+        let mut service = MidiService::with_source(Box::new(source.clone()));
+        service.set_sink(Box::new(RecordingSink::new())).unwrap();
+        assert_eq!(service.init().unwrap(), 1);
+        // As if the only port had gone away
+        service.outputs.lock().unwrap().clear();
+
+        source.set_devices(vec![device(1), device(2)]);
+        assert!(service.poll().is_err());
+        assert!(matches!(
+            service.take_events().as_slice(),
+            [ServiceEvent::DeviceConnected(info)] if info.device_id == 2
+        ));
+    }
+
+    #[test]
+    fn toggled_modifier_survives_config_update() {
+        let mut service = MidiService::with_source(Box::new(ScriptedSource::new()));
+        let toggle = ModifierConfig {
+            mode: ModifierMode::Toggle,
+            ..ModifierConfig::new(HIDCodes::X as u8, 12)
+        };
+        service.set_modifiers(std::slice::from_ref(&toggle));
+        service.modifiers[0].update_value(1.0);
+        service.modifiers[0].update_value(0.0);
+        assert_eq!(service.shift_amount(), 12);
+
+        // Changing the shift keeps it toggled on
+        service.set_modifiers(&[ModifierConfig {
+            shift: 7,
+            ..toggle.clone()
+        }]);
+        assert_eq!(service.shift_amount(), 7);
+
+        // A different mode starts off again
+        service.set_modifiers(&[ModifierConfig {
+            mode: ModifierMode::Momentary,
+            ..toggle
+        }]);
+        assert_eq!(service.shift_amount(), 0);
+    }
+
+    #[test]
+    fn removing_output_releases_mpe_member_channel() {
+        let source = ScriptedSource::new();
+        let sink = RecordingSink::new();
+        let mut service = port_service(&source, &sink);
+        service
+            .set_mpe(&MpeConfig {
+                enabled: true,
+                ..Default::default()
+            })
+            .unwrap();
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        service.poll().unwrap();
+        assert!(sent(&sink).contains(&SinkMessage::NoteOn {
+            note_id: 60,
+            velocity: 0.8,
+            channel: 1
+        }));
+
+        service.disconnect_port("Synth").unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOff {
+                note_id: 60,
+                velocity: DEFAULT_RELEASE_VELOCITY,
+                channel: 1
+            }]
+        );
+    }
+
+    #[test]
+    fn removing_output_releases_sustained_notes() {
+        let source = ScriptedSource::new();
+        let sink = RecordingSink::new();
+        let mut service = port_service(&source, &sink);
+        service
+            .outputs
+            .lock()
+            .unwrap()
+            .set_sustained_channels(&[0])
+            .unwrap();
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        source.push_device_frame(1, frame(&[]));
+        service.poll().unwrap();
+        service.poll().unwrap();
+        // The note off is held back by the pedal
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOn {
+                note_id: 60,
+                velocity: 0.8,
+                channel: 0
+            }]
+        );
+
+        service.disconnect_port("Synth").unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOff {
+                note_id: 60,
+                velocity: DEFAULT_RELEASE_VELOCITY,
+                channel: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn arp_thread_wakes_on_hold() {
+        let source = ScriptedSource::new();
+        let sink = RecordingSink::new();
+        let mut service = port_service(&source, &sink);
+        service
+            .set_arp(&ArpConfig {
+                enabled: true,
+                ..Default::default()
+            })
+            .unwrap();
+        // Nothing is held yet, so the thread is waiting without a timeout
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        service.poll().unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        assert_eq!(
+            sent(&sink).first(),
+            Some(&SinkMessage::NoteOn {
+                note_id: 60,
+                velocity: 0.8,
+                channel: 0
+            })
+        );
+        // Stops the thread
+        drop(service);
+    }
+
+    #[test]
+    fn removing_output_releases_arp_step() {
+        let source = ScriptedSource::new();
+        let sink = RecordingSink::new();
+        let mut service = port_service(&source, &sink);
+        // Stepped by hand rather than on the arpeggiator's thread
+        service.outputs.lock().unwrap().set_arp(&ArpConfig {
+            enabled: true,
+            ..Default::default()
+        });
+        source.push_device_frame(1, frame(&[(HIDCodes::A, 0.8)]));
+        service.poll().unwrap();
+        service
+            .outputs
+            .lock()
+            .unwrap()
+            .arp_tick(Instant::now())
+            .unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOn {
+                note_id: 60,
+                velocity: 0.8,
+                channel: 0
+            }]
+        );
+
+        service.disconnect_port("Synth").unwrap();
+        assert_eq!(
+            sent(&sink),
+            vec![SinkMessage::NoteOff {
+                note_id: 60,
+                velocity: DEFAULT_RELEASE_VELOCITY,
+                channel: 0
+            }]
+        );
+    }
+}
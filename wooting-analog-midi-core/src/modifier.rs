@@ -0,0 +1,69 @@
+use crate::ACTUATION_POINT;
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ModifierMode {
+    // The shift is applied while the key is held
+    #[default]
+    Momentary,
+    // Each press turns the shift on or off
+    Toggle,
+}
+
+fn default_actuation_point() -> f32 {
+    ACTUATION_POINT
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModifierConfig {
+    // HID code of the key acting as the modifier
+    pub key: u8,
+    // Semitones (or scale steps, depending on the scale's transpose mode) added to every note while the modifier is active
+    pub shift: i8,
+    #[serde(default = "default_actuation_point")]
+    pub actuation_point: f32,
+    #[serde(default)]
+    pub mode: ModifierMode,
+}
+
+impl ModifierConfig {
+    pub fn new(key: u8, shift: i8) -> Self {
+        ModifierConfig {
+            key,
+            shift,
+            actuation_point: default_actuation_point(),
+            mode: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Modifier {
+    pub config: ModifierConfig,
+    pub pressed: bool,
+    pub active: bool,
+}
+
+impl Modifier {
+    pub fn new(config: ModifierConfig) -> Self {
+        Modifier {
+            config,
+            pressed: false,
+            active: false,
+        }
+    }
+
+    pub fn update_value(&mut self, value: f32) {
+        let pressed = value >= self.config.actuation_point;
+        match self.config.mode {
+            ModifierMode::Momentary => self.active = pressed,
+            ModifierMode::Toggle => {
+                if pressed && !self.pressed {
+                    self.active = !self.active;
+                }
+            }
+        }
+        self.pressed = pressed;
+    }
+}
@@ -0,0 +1,139 @@
+use crate::{Channel, NoteID};
+use serde::{Deserialize, Serialize};
+
+// Registered Parameter 6 is the MPE Configuration Message, which sets up the zone on the master channel
+const MCM_RPN_LSB: u8 = 6;
+// Controller which MPE synths use for timbre
+pub const TIMBRE_CC: u8 = 74;
+const MAX_MEMBER_CHANNELS: u8 = 15;
+
+fn default_member_channels() -> u8 {
+    MAX_MEMBER_CHANNELS
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum MpeZone {
+    // Master channel 1, member channels counting up from 2
+    #[default]
+    Lower,
+    // Master channel 16, member channels counting down from 15
+    Upper,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MpeConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default)]
+    pub zone: MpeZone,
+    #[serde(default = "default_member_channels")]
+    pub member_channels: u8,
+    // Also sends the key depth as timbre (CC74)
+    #[serde(default)]
+    pub timbre: bool,
+}
+
+impl Default for MpeConfig {
+    fn default() -> Self {
+        MpeConfig {
+            enabled: false,
+            zone: MpeZone::default(),
+            member_channels: default_member_channels(),
+            timbre: false,
+        }
+    }
+}
+
+impl MpeConfig {
+    pub fn master_channel(&self) -> Channel {
+        match self.zone {
+            MpeZone::Lower => 0,
+            MpeZone::Upper => 15,
+        }
+    }
+
+    pub fn member_count(&self) -> u8 {
+        self.member_channels.clamp(1, MAX_MEMBER_CHANNELS)
+    }
+
+    fn member_channels(&self) -> Vec<Channel> {
+        let count = self.member_count();
+        match self.zone {
+            MpeZone::Lower => (1..=count).collect(),
+            MpeZone::Upper => (15 - count..15).rev().collect(),
+        }
+    }
+
+    // Messages which set up the zone on the receiver, or turn it off when `enabled` is false
+    pub fn configuration_messages(&self) -> Vec<[u8; 3]> {
+        let status = 0xB0 | self.master_channel();
+        let member_count = if self.enabled { self.member_count() } else { 0 };
+        vec![
+            [status, 101, 0],
+            [status, 100, MCM_RPN_LSB],
+            [status, 6, member_count],
+            // Deselect the RPN so stray data entry messages don't change it
+            [status, 101, 127],
+            [status, 100, 127],
+        ]
+    }
+}
+
+// Gives each sounding note its own member channel
+#[derive(Debug, Clone)]
+pub struct MpeAllocator {
+    config: MpeConfig,
+    // Member channels with the note (and the channel it was originally on) they're playing. The least recently used channel comes first
+    channels: Vec<(Channel, Option<(NoteID, Channel)>)>,
+}
+
+impl MpeAllocator {
+    pub fn new(config: MpeConfig) -> Self {
+        let channels = config
+            .member_channels()
+            .into_iter()
+            .map(|channel| (channel, None))
+            .collect();
+        MpeAllocator { config, channels }
+    }
+
+    pub fn config(&self) -> &MpeConfig {
+        &self.config
+    }
+
+    // Returns the member channel for the note, along with the note that had to be stopped to free it up if all channels were in use
+    pub fn allocate(
+        &mut self,
+        note_id: NoteID,
+        channel: Channel,
+    ) -> (Channel, Option<(NoteID, Channel)>) {
+        let index = self
+            .channels
+            .iter()
+            .position(|(_, note)| note.is_none())
+            .unwrap_or(0);
+        let (member, stolen) = self.channels.remove(index);
+        self.channels.push((member, Some((note_id, channel))));
+        (member, stolen.map(|(stolen_note, _)| (stolen_note, member)))
+    }
+
+    pub fn member(&self, note_id: NoteID, channel: Channel) -> Option<Channel> {
+        self.channels
+            .iter()
+            .find(|(_, note)| *note == Some((note_id, channel)))
+            .map(|(member, _)| *member)
+    }
+
+    // Frees the member channel the note was on. Returns None if the note had been stolen
+    pub fn release(&mut self, note_id: NoteID, channel: Channel) -> Option<Channel> {
+        let index = self
+            .channels
+            .iter()
+            .position(|(_, note)| *note == Some((note_id, channel)))?;
+        let (member, _) = self.channels.remove(index);
+        // Reusing the channel which was released longest ago leaves the release tails alone for as long as possible
+        self.channels.push((member, None));
+        Some(member)
+    }
+}
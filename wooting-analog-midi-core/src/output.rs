@@ -0,0 +1,561 @@
+use crate::arpeggiator::{ArpConfig, Arpeggiator};
+use crate::mpe::{MpeAllocator, MpeConfig, TIMBRE_CC};
+use crate::{Channel, NoteID, NoteSink, DEFAULT_RELEASE_VELOCITY};
+use anyhow::Result;
+use midir::{MidiOutput, MidiOutputConnection, MidiOutputPort};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar};
+use std::time::Instant;
+
+// Name of the virtual output port we publish on platforms which support it (ALSA & CoreMIDI)
+pub const VIRTUAL_PORT_NAME: &str = "Wooting Analog MIDI";
+const CLIENT_NAME: &str = "Wooting Analog MIDI Output";
+const CONNECTION_NAME: &str = "wooting-analog-midi";
+
+// Whether the port is our own virtual output, which shows up as a regular port while it exists. Connecting to it would loop back to us
+#[cfg(unix)]
+pub fn is_virtual_port(name: &str) -> bool {
+    // CoreMIDI lists it by its port name, ALSA as "client:port client_id:port_id"
+    name == VIRTUAL_PORT_NAME
+        || name.starts_with(&format!("{}:{} ", CLIENT_NAME, VIRTUAL_PORT_NAME))
+}
+
+// There's no virtual port on other platforms
+#[cfg(not(unix))]
+pub fn is_virtual_port(_name: &str) -> bool {
+    false
+}
+
+pub fn midi_output() -> Result<MidiOutput> {
+    Ok(MidiOutput::new(CLIENT_NAME)?)
+}
+
+// The ports that can be selected as the output. Real ports are listed first, followed by the virtual port where it's supported, which is represented by `None`
+pub fn output_ports(midi_out: &MidiOutput) -> Vec<(Option<MidiOutputPort>, String)> {
+    #[allow(unused_mut)]
+    let mut ports: Vec<(Option<MidiOutputPort>, String)> = midi_out
+        .ports()
+        .into_iter()
+        .filter_map(|port| {
+            let name = midi_out.port_name(&port).ok()?;
+            if is_virtual_port(&name) {
+                None
+            } else {
+                Some((Some(port), name))
+            }
+        })
+        .collect();
+    #[cfg(unix)]
+    ports.push((None, VIRTUAL_PORT_NAME.to_string()));
+    ports
+}
+
+pub fn connect_output(
+    midi_out: MidiOutput,
+    port: Option<&MidiOutputPort>,
+) -> Result<MidiOutputConnection> {
+    match port {
+        Some(port) => midi_out
+            .connect(port, CONNECTION_NAME)
+            .map_err(|e| anyhow!("Error: {}", e)),
+        None => create_virtual_output(midi_out),
+    }
+}
+
+#[cfg(unix)]
+fn create_virtual_output(midi_out: MidiOutput) -> Result<MidiOutputConnection> {
+    use midir::os::unix::VirtualOutput;
+    midi_out
+        .create_virtual(VIRTUAL_PORT_NAME)
+        .map_err(|e| anyhow!("Failed to create virtual port: {}", e))
+}
+
+#[cfg(not(unix))]
+fn create_virtual_output(_midi_out: MidiOutput) -> Result<MidiOutputConnection> {
+    bail!("Virtual ports aren't supported on this platform")
+}
+
+// Which channels get sent to an output
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OutputRouting {
+    #[default]
+    All,
+    Channels {
+        channels: Vec<Channel>,
+    },
+}
+
+impl OutputRouting {
+    pub fn includes(&self, channel: Channel) -> bool {
+        match self {
+            OutputRouting::All => true,
+            OutputRouting::Channels { channels } => channels.contains(&channel),
+        }
+    }
+}
+
+// A port we want to output to
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct OutputConfig {
+    pub port: String,
+    #[serde(default)]
+    pub routing: OutputRouting,
+}
+
+// A sink which only receives the channels its routing includes
+pub struct Output {
+    // None when the sink was provided directly rather than connected to a port
+    port: Option<String>,
+    routing: OutputRouting,
+    sink: Box<dyn NoteSink>,
+}
+
+impl Output {
+    pub fn new(port: Option<String>, routing: OutputRouting, sink: Box<dyn NoteSink>) -> Self {
+        Output {
+            port,
+            routing,
+            sink,
+        }
+    }
+
+    pub fn port(&self) -> Option<&String> {
+        self.port.as_ref()
+    }
+
+    pub fn routing(&self) -> &OutputRouting {
+        &self.routing
+    }
+
+    pub fn set_routing(&mut self, routing: OutputRouting) {
+        self.routing = routing;
+    }
+}
+
+impl NoteSink for Output {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.note_on(note_id, velocity, channel)?;
+        }
+        Ok(())
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.note_off(note_id, velocity, channel)?;
+        }
+        Ok(())
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink
+                .polyphonic_aftertouch(note_id, pressure, channel)?;
+        }
+        Ok(())
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.channel_pressure(pressure, channel)?;
+        }
+        Ok(())
+    }
+
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.control_change(control, value, channel)?;
+        }
+        Ok(())
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.pitch_bend(value, channel)?;
+        }
+        Ok(())
+    }
+
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()> {
+        if self.routing.includes(channel) {
+            self.sink.pitch_bend_range(semitones, channel)?;
+        }
+        Ok(())
+    }
+
+    fn raw_message(&mut self, message: &[u8]) -> Result<()> {
+        // System messages don't have a channel, so they go to every output
+        let routed = match message.first() {
+            Some(status) if *status < 0xF0 => self.routing.includes(status & 0x0F),
+            _ => true,
+        };
+        if routed {
+            self.sink.raw_message(message)?;
+        }
+        Ok(())
+    }
+}
+
+// The connected outputs, messages are sent to each output that the channel is routed to
+#[derive(Default)]
+struct Destinations {
+    outputs: Vec<Output>,
+    // When MPE is on, every note gets moved to its own member channel and the channel wide messages go to the master channel
+    mpe: Option<MpeAllocator>,
+}
+
+impl Destinations {
+    // Where channel wide messages for the channel should go
+    fn channel_target(&self, channel: Channel) -> Channel {
+        self.mpe
+            .as_ref()
+            .map_or(channel, |mpe| mpe.config().master_channel())
+    }
+
+    fn find_mut(&mut self, port: &str) -> Option<&mut Output> {
+        self.outputs
+            .iter_mut()
+            .find(|output| output.port().map(|name| name.as_str()) == Some(port))
+    }
+
+    // Sends note offs for the notes to just the one output, for when it stops receiving them. They stay allocated as they're still on at the other outputs
+    fn release_output(&mut self, port: &str, notes: &[(NoteID, Channel)]) -> Result<()> {
+        let output = self
+            .outputs
+            .iter_mut()
+            .find(|output| output.port().map(|name| name.as_str()) == Some(port));
+        if let Some(output) = output {
+            for (note_id, channel) in notes.iter() {
+                let channel = match self.mpe.as_ref() {
+                    Some(mpe) => match mpe.member(*note_id, *channel) {
+                        Some(member) => member,
+                        // Got stopped when its channel was stolen
+                        None => continue,
+                    },
+                    None => *channel,
+                };
+                output.note_off(*note_id, DEFAULT_RELEASE_VELOCITY, channel)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Sends to every output even if one of them fails, returning the last error
+    fn send_all(&mut self, mut send: impl FnMut(&mut Output) -> Result<()>) -> Result<()> {
+        let mut result = Ok(());
+        for output in self.outputs.iter_mut() {
+            if let Err(e) = send(output) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+}
+
+impl NoteSink for Destinations {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let channel = match self.mpe.as_mut() {
+            Some(mpe) => {
+                let (member, stolen) = mpe.allocate(note_id, channel);
+                if let Some((stolen_note, stolen_channel)) = stolen {
+                    self.send_all(|output| {
+                        output.note_off(stolen_note, DEFAULT_RELEASE_VELOCITY, stolen_channel)
+                    })?;
+                }
+                // The member channel may still have the pressure of the last note it played
+                self.send_all(|output| output.channel_pressure(0.0, member))?;
+                member
+            }
+            None => channel,
+        };
+        self.send_all(|output| output.note_on(note_id, velocity, channel))
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        let channel = match self.mpe.as_mut() {
+            Some(mpe) => match mpe.release(note_id, channel) {
+                Some(member) => member,
+                // The note already got stopped when its channel was stolen
+                None => return Ok(()),
+            },
+            None => channel,
+        };
+        self.send_all(|output| output.note_off(note_id, velocity, channel))
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        match self.mpe.as_ref() {
+            // Each note has its own channel, so the pressure can be sent as channel pressure which more synths support
+            Some(mpe) => {
+                if let Some(member) = mpe.member(note_id, channel) {
+                    let timbre = mpe.config().timbre;
+                    self.send_all(|output| output.channel_pressure(pressure, member))?;
+                    if timbre {
+                        self.send_all(|output| output.control_change(TIMBRE_CC, pressure, member))?;
+                    }
+                }
+                Ok(())
+            }
+            None => {
+                self.send_all(|output| output.polyphonic_aftertouch(note_id, pressure, channel))
+            }
+        }
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        let channel = self.channel_target(channel);
+        self.send_all(|output| output.channel_pressure(pressure, channel))
+    }
+
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()> {
+        let channel = self.channel_target(channel);
+        self.send_all(|output| output.control_change(control, value, channel))
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        let channel = self.channel_target(channel);
+        self.send_all(|output| output.pitch_bend(value, channel))
+    }
+
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()> {
+        let channel = self.channel_target(channel);
+        self.send_all(|output| output.pitch_bend_range(semitones, channel))
+    }
+
+    fn raw_message(&mut self, message: &[u8]) -> Result<()> {
+        self.send_all(|output| output.raw_message(message))
+    }
+}
+
+// All the active outputs. Notes pass through the software sustain and the arpeggiator before they're sent out
+#[derive(Default)]
+pub struct Outputs {
+    destinations: Destinations,
+    // When the arpeggiator is on, the notes that are sent in are held by it and it plays them in steps instead
+    arp: Option<Arpeggiator>,
+    // Channels with the software sustain pedal down, along with the note offs that are being held back
+    sustain: HashMap<Channel, Vec<(NoteID, f32)>>,
+    // Wakes the arpeggiator's thread when there's something new for it to play
+    arp_wake: Arc<Condvar>,
+}
+
+impl Outputs {
+    pub fn is_empty(&self) -> bool {
+        self.destinations.outputs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Output> {
+        self.destinations.outputs.iter()
+    }
+
+    pub fn get(&self, port: &str) -> Option<&Output> {
+        self.destinations
+            .outputs
+            .iter()
+            .find(|output| output.port().map(|name| name.as_str()) == Some(port))
+    }
+
+    pub fn push(&mut self, output: Output) {
+        self.destinations.outputs.push(output);
+    }
+
+    pub fn remove(&mut self, port: &str) -> Option<Output> {
+        let outputs = &mut self.destinations.outputs;
+        let index = outputs
+            .iter()
+            .position(|output| output.port().map(|name| name.as_str()) == Some(port))?;
+        Some(outputs.remove(index))
+    }
+
+    pub fn set_routing(&mut self, port: &str, routing: OutputRouting) {
+        if let Some(output) = self.destinations.find_mut(port) {
+            output.set_routing(routing);
+        }
+    }
+
+    // Stops the notes on the output, which are given as they were sent in. Used before the output gets removed or re-routed, so nothing is left stuck on it
+    pub fn release_output(&mut self, port: &str, notes: &[(NoteID, Channel)]) -> Result<()> {
+        match self.arp.as_ref() {
+            // Held notes only sound through the arpeggiator's steps
+            Some(arp) => {
+                let sounding: Vec<(NoteID, Channel)> = arp.sounding().into_iter().collect();
+                self.destinations.release_output(port, &sounding)
+            }
+            None => {
+                // Notes whose keys were released while the pedal is down are still sounding too
+                let sustained = self.sustain.iter().flat_map(|(channel, notes)| {
+                    notes.iter().map(move |(note_id, _)| (*note_id, *channel))
+                });
+                let notes: Vec<(NoteID, Channel)> =
+                    notes.iter().cloned().chain(sustained).collect();
+                self.destinations.release_output(port, &notes)
+            }
+        }
+    }
+
+    // Dropping the outputs closes the underlying connections
+    pub fn clear(&mut self) {
+        self.destinations.outputs.clear();
+    }
+
+    pub fn mpe(&self) -> Option<&MpeConfig> {
+        self.destinations.mpe.as_ref().map(|mpe| mpe.config())
+    }
+
+    // All notes should be released before this is changed, as the channels they were sent on would get lost
+    pub fn set_mpe(&mut self, config: &MpeConfig) {
+        self.destinations.mpe = if config.enabled {
+            Some(MpeAllocator::new(config.clone()))
+        } else {
+            None
+        };
+    }
+
+    pub fn arp(&self) -> Option<&ArpConfig> {
+        self.arp.as_ref().map(|arp| arp.config())
+    }
+
+    // Notes should be released before the arpeggiator is turned on or off, changing the other settings keeps the held notes
+    pub fn set_arp(&mut self, config: &ArpConfig) {
+        match self.arp.as_mut() {
+            Some(arp) if config.enabled => arp.set_config(config.clone()),
+            _ if config.enabled => self.arp = Some(Arpeggiator::new(config.clone())),
+            _ => self.arp = None,
+        }
+        self.arp_wake.notify_all();
+    }
+
+    pub fn arp_wake(&self) -> Arc<Condvar> {
+        self.arp_wake.clone()
+    }
+
+    pub fn set_arp_depth(&mut self, note_id: NoteID, channel: Channel, depth: f32) {
+        if let Some(arp) = self.arp.as_mut() {
+            arp.set_depth(note_id, channel, depth);
+        }
+    }
+
+    // Plays the arpeggiator steps that are due, returning when it should next be called
+    pub fn arp_tick(&mut self, now: Instant) -> Result<Option<Instant>> {
+        match self.arp.as_mut() {
+            Some(arp) => arp.tick(now, &mut self.destinations),
+            None => Ok(None),
+        }
+    }
+
+    // Holds back note offs on the given channels, sending the ones which were held back on channels that aren't in here anymore
+    pub fn set_sustained_channels(&mut self, channels: &[Channel]) -> Result<()> {
+        let released: Vec<Channel> = self
+            .sustain
+            .keys()
+            .filter(|channel| !channels.contains(channel))
+            .cloned()
+            .collect();
+        for channel in released {
+            if let Some(notes) = self.sustain.remove(&channel) {
+                for (note_id, velocity) in notes {
+                    self.release_note(note_id, velocity, channel)?;
+                }
+            }
+        }
+        for channel in channels.iter() {
+            self.sustain.entry(*channel).or_default();
+        }
+        Ok(())
+    }
+
+    // Note off which has made it past the sustain
+    fn release_note(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        match self.arp.as_mut() {
+            Some(arp) => arp.release(note_id, channel, &mut self.destinations),
+            None => self.destinations.note_off(note_id, velocity, channel),
+        }
+    }
+
+    pub fn as_sink(&mut self) -> Option<&mut dyn NoteSink> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self)
+        }
+    }
+}
+
+impl NoteSink for Outputs {
+    fn note_on(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        // A note that's still being sustained gets stopped before it's played again
+        let sustained = self.sustain.get_mut(&channel).and_then(|notes| {
+            let index = notes
+                .iter()
+                .position(|(sustained, _)| *sustained == note_id)?;
+            Some(notes.remove(index))
+        });
+        if let Some((_, release_velocity)) = sustained {
+            self.release_note(note_id, release_velocity, channel)?;
+        }
+        match self.arp.as_mut() {
+            Some(arp) => {
+                arp.hold(note_id, velocity, channel);
+                self.arp_wake.notify_all();
+                Ok(())
+            }
+            None => self.destinations.note_on(note_id, velocity, channel),
+        }
+    }
+
+    fn note_off(&mut self, note_id: NoteID, velocity: f32, channel: Channel) -> Result<()> {
+        match self.sustain.get_mut(&channel) {
+            Some(notes) => {
+                notes.push((note_id, velocity));
+                Ok(())
+            }
+            None => self.release_note(note_id, velocity, channel),
+        }
+    }
+
+    fn polyphonic_aftertouch(
+        &mut self,
+        note_id: NoteID,
+        pressure: f32,
+        channel: Channel,
+    ) -> Result<()> {
+        // Held notes only sound through the arpeggiator's steps
+        if self.arp.is_some() {
+            return Ok(());
+        }
+        self.destinations
+            .polyphonic_aftertouch(note_id, pressure, channel)
+    }
+
+    fn channel_pressure(&mut self, pressure: f32, channel: Channel) -> Result<()> {
+        self.destinations.channel_pressure(pressure, channel)
+    }
+
+    fn control_change(&mut self, control: u8, value: f32, channel: Channel) -> Result<()> {
+        self.destinations.control_change(control, value, channel)
+    }
+
+    fn pitch_bend(&mut self, value: f32, channel: Channel) -> Result<()> {
+        self.destinations.pitch_bend(value, channel)
+    }
+
+    fn pitch_bend_range(&mut self, semitones: u8, channel: Channel) -> Result<()> {
+        self.destinations.pitch_bend_range(semitones, channel)
+    }
+
+    fn raw_message(&mut self, message: &[u8]) -> Result<()> {
+        self.destinations.raw_message(message)
+    }
+}
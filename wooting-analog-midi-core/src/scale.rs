@@ -0,0 +1,179 @@
+use crate::NoteID;
+use serde::{Deserialize, Serialize};
+
+fn default_root() -> NoteID {
+    60
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Scale {
+    #[default]
+    Major,
+    // Natural minor
+    Minor,
+    HarmonicMinor,
+    MelodicMinor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+    Chromatic,
+    // Semitones above the root of each degree within an octave
+    Custom {
+        intervals: Vec<u8>,
+    },
+}
+
+impl Scale {
+    // Semitones above the root of each degree, always starting with the root and sorted
+    pub fn intervals(&self) -> Vec<u8> {
+        let intervals: &[u8] = match self {
+            Scale::Major => &[0, 2, 4, 5, 7, 9, 11],
+            Scale::Minor => &[0, 2, 3, 5, 7, 8, 10],
+            Scale::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            Scale::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            Scale::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            Scale::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            Scale::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            Scale::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            Scale::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            Scale::MajorPentatonic => &[0, 2, 4, 7, 9],
+            Scale::MinorPentatonic => &[0, 3, 5, 7, 10],
+            Scale::Blues => &[0, 3, 5, 6, 7, 10],
+            Scale::Chromatic => &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+            Scale::Custom { intervals } => intervals,
+        };
+        // User defined intervals may be out of order or go past the octave
+        let mut intervals: Vec<u8> = intervals.iter().map(|interval| interval % 12).collect();
+        intervals.push(0);
+        intervals.sort_unstable();
+        intervals.dedup();
+        intervals
+    }
+}
+
+// What the transpose modifiers move notes by
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum TransposeMode {
+    #[default]
+    Semitones,
+    // Notes move along the scale, so they stay in key
+    ScaleSteps,
+}
+
+// The key which scale degree mappings are played in
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ScaleConfig {
+    // Note which degree 0 is mapped to
+    #[serde(default = "default_root")]
+    pub root: NoteID,
+    #[serde(default)]
+    pub scale: Scale,
+    #[serde(default)]
+    pub transpose_mode: TransposeMode,
+}
+
+impl Default for ScaleConfig {
+    fn default() -> Self {
+        ScaleConfig {
+            root: default_root(),
+            scale: Default::default(),
+            transpose_mode: Default::default(),
+        }
+    }
+}
+
+impl ScaleConfig {
+    // Degrees past the end of the scale carry on into the next octave, negative degrees go below the root
+    pub fn note(&self, degree: i16) -> Option<NoteID> {
+        let intervals = self.scale.intervals();
+        // Widened, as a large degree of a short scale is many octaves away
+        let degree = degree as i32;
+        let length = intervals.len() as i32;
+        let octave = degree.div_euclid(length);
+        let interval = intervals[degree.rem_euclid(length) as usize] as i32;
+        let note = self.root as i32 + octave * 12 + interval;
+        if (0..=127).contains(&note) {
+            Some(note as NoteID)
+        } else {
+            None
+        }
+    }
+
+    // Degree of the note, or of the closest note of the scale below it when it isn't in the scale
+    pub fn degree(&self, note: NoteID) -> i16 {
+        let intervals = self.scale.intervals();
+        let offset = note as i16 - self.root as i16;
+        let octave = offset.div_euclid(12);
+        let semitone = offset.rem_euclid(12) as u8;
+        let index = intervals
+            .iter()
+            .rposition(|interval| *interval <= semitone)
+            .unwrap_or(0);
+        octave * intervals.len() as i16 + index as i16
+    }
+}
+
+// How far notes get moved from the pitch they're mapped to
+#[derive(Debug, Clone, Copy)]
+pub struct Transpose<'a> {
+    pub semitones: i8,
+    pub steps: i8,
+    pub scale: &'a ScaleConfig,
+}
+
+impl<'a> Transpose<'a> {
+    // The modifier shift is applied in the scale's transpose mode
+    pub fn new(shift: i8, scale: &'a ScaleConfig) -> Self {
+        match scale.transpose_mode {
+            TransposeMode::Semitones => Transpose {
+                semitones: shift,
+                steps: 0,
+                scale,
+            },
+            TransposeMode::ScaleSteps => Transpose {
+                semitones: 0,
+                steps: shift,
+                scale,
+            },
+        }
+    }
+
+    pub fn with_semitones(self, semitones: i8) -> Self {
+        Transpose {
+            semitones: self.semitones.saturating_add(semitones),
+            ..self
+        }
+    }
+
+    fn add_semitones(&self, note: i16) -> Option<NoteID> {
+        let note = note + self.semitones as i16;
+        if (0..=127).contains(&note) {
+            Some(note as NoteID)
+        } else {
+            None
+        }
+    }
+
+    // Notes which aren't in the scale snap to the scale note below them when they're moved by steps
+    pub fn apply(&self, note: NoteID) -> Option<NoteID> {
+        let note = if self.steps == 0 {
+            note
+        } else {
+            self.scale
+                .note(self.scale.degree(note).checked_add(self.steps as i16)?)?
+        };
+        self.add_semitones(note as i16)
+    }
+
+    pub fn apply_degree(&self, degree: i16) -> Option<NoteID> {
+        let note = self.scale.note(degree.checked_add(self.steps as i16)?)?;
+        self.add_semitones(note as i16)
+    }
+}
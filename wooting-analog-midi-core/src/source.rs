@@ -0,0 +1,234 @@
+use crate::sdk;
+use anyhow::Result;
+use sdk::{DeviceEventType, DeviceID, DeviceInfo, WootingAnalogResult};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+pub type DeviceEventCallback = Box<dyn Fn(DeviceEventType, DeviceInfo) + Send>;
+
+// Where `MidiService` gets its analog key data from. Keys are HID codes, values are the analog depth between 0.0 and 1.0
+pub trait AnalogSource: Send {
+    fn initialise(&mut self) -> Result<u32>;
+    fn connected_devices(&self) -> Result<Vec<DeviceInfo>>;
+    fn read_full_buffer(&mut self, max_length: usize) -> Result<HashMap<u16, f32>>;
+    fn read_device_buffer(
+        &mut self,
+        max_length: usize,
+        device_id: DeviceID,
+    ) -> Result<HashMap<u16, f32>>;
+    // The callback gets called whenever a device is connected or disconnected, possibly from another thread
+    fn set_device_event_callback(&mut self, callback: DeviceEventCallback) -> Result<()>;
+    fn clear_device_event_callback(&mut self);
+    fn uninitialise(&mut self);
+}
+
+// Reads from the Wooting Analog SDK, this requires the SDK to be installed and a device to be connected
+#[derive(Debug, Default)]
+pub struct SdkSource {
+    max_devices: usize,
+}
+
+impl SdkSource {
+    pub fn new(max_devices: usize) -> Self {
+        SdkSource { max_devices }
+    }
+}
+
+impl AnalogSource for SdkSource {
+    fn initialise(&mut self) -> Result<u32> {
+        Ok(sdk::initialise().0?)
+    }
+
+    fn connected_devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(sdk::get_connected_devices_info(self.max_devices).0?)
+    }
+
+    fn read_full_buffer(&mut self, max_length: usize) -> Result<HashMap<u16, f32>> {
+        Ok(sdk::read_full_buffer(max_length).0?)
+    }
+
+    fn read_device_buffer(
+        &mut self,
+        max_length: usize,
+        device_id: DeviceID,
+    ) -> Result<HashMap<u16, f32>> {
+        Ok(sdk::read_full_buffer_device(max_length, device_id).0?)
+    }
+
+    fn set_device_event_callback(&mut self, callback: DeviceEventCallback) -> Result<()> {
+        Ok(sdk::set_device_event_cb(callback).0?)
+    }
+
+    fn clear_device_event_callback(&mut self) {
+        sdk::clear_device_event_cb();
+    }
+
+    fn uninitialise(&mut self) {
+        sdk::uninitialise();
+    }
+}
+
+#[derive(Debug, Default)]
+struct ScriptedFrames {
+    frames: VecDeque<HashMap<u16, f32>>,
+    current: HashMap<u16, f32>,
+}
+
+impl ScriptedFrames {
+    fn next(&mut self, max_length: usize) -> HashMap<u16, f32> {
+        if let Some(frame) = self.frames.pop_front() {
+            self.current = frame;
+        }
+        self.current
+            .iter()
+            .filter(|(_, value)| **value > 0.0)
+            .take(max_length)
+            .map(|(code, value)| (*code, *value))
+            .collect()
+    }
+}
+
+#[derive(Default)]
+struct ScriptedState {
+    merged: ScriptedFrames,
+    devices: Vec<DeviceInfo>,
+    device_frames: HashMap<DeviceID, ScriptedFrames>,
+    // Devices whose next read fails
+    failing_reads: Vec<DeviceID>,
+    event_callback: Option<DeviceEventCallback>,
+}
+
+impl std::fmt::Debug for ScriptedState {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("ScriptedState")
+            .field("merged", &self.merged)
+            .field("devices", &self.devices)
+            .field("device_frames", &self.device_frames)
+            .field("failing_reads", &self.failing_reads)
+            .finish()
+    }
+}
+
+// Source which plays back pre-defined frames, one per read. Once the frames run out the last frame keeps being returned, so a key stays held until a frame releases it.
+// Frames pushed with `push_frame` are read when no devices are set, otherwise each device plays back the frames pushed for it with `push_device_frame`.
+// Clones share the same state, so frames can be pushed while a clone is owned by the `MidiService`
+#[derive(Debug, Clone, Default)]
+pub struct ScriptedSource {
+    state: Arc<Mutex<ScriptedState>>,
+}
+
+impl ScriptedSource {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    pub fn with_frames(frames: impl IntoIterator<Item = HashMap<u16, f32>>) -> Self {
+        let source = Self::new();
+        source.state.lock().unwrap().merged.frames.extend(frames);
+        source
+    }
+
+    pub fn push_frame(&self, frame: HashMap<u16, f32>) {
+        self.state.lock().unwrap().merged.frames.push_back(frame);
+    }
+
+    pub fn push_device_frame(&self, device_id: DeviceID, frame: HashMap<u16, f32>) {
+        self.state
+            .lock()
+            .unwrap()
+            .device_frames
+            .entry(device_id)
+            .or_default()
+            .frames
+            .push_back(frame);
+    }
+
+    // Makes the next read of the device fail, as if it had a hiccup while staying connected
+    pub fn fail_next_read(&self, device_id: DeviceID) {
+        self.state.lock().unwrap().failing_reads.push(device_id);
+    }
+
+    pub fn remaining_frames(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.merged.frames.len()
+            + state
+                .device_frames
+                .values()
+                .map(|device| device.frames.len())
+                .sum::<usize>()
+    }
+
+    // Replaces the connected devices, firing the device event callback for every device which got connected or disconnected
+    pub fn set_devices(&self, devices: Vec<DeviceInfo>) {
+        let mut state = self.state.lock().unwrap();
+        let previous = std::mem::replace(&mut state.devices, devices);
+        if let Some(callback) = state.event_callback.as_ref() {
+            for device in previous.iter() {
+                if !state
+                    .devices
+                    .iter()
+                    .any(|current| current.device_id == device.device_id)
+                {
+                    callback(DeviceEventType::Disconnected, device.clone());
+                }
+            }
+            for device in state.devices.iter() {
+                if !previous
+                    .iter()
+                    .any(|previous| previous.device_id == device.device_id)
+                {
+                    callback(DeviceEventType::Connected, device.clone());
+                }
+            }
+        }
+    }
+}
+
+impl AnalogSource for ScriptedSource {
+    fn initialise(&mut self) -> Result<u32> {
+        Ok(self.state.lock().unwrap().devices.len() as u32)
+    }
+
+    fn connected_devices(&self) -> Result<Vec<DeviceInfo>> {
+        Ok(self.state.lock().unwrap().devices.clone())
+    }
+
+    fn read_full_buffer(&mut self, max_length: usize) -> Result<HashMap<u16, f32>> {
+        Ok(self.state.lock().unwrap().merged.next(max_length))
+    }
+
+    fn read_device_buffer(
+        &mut self,
+        max_length: usize,
+        device_id: DeviceID,
+    ) -> Result<HashMap<u16, f32>> {
+        let mut state = self.state.lock().unwrap();
+        if !state
+            .devices
+            .iter()
+            .any(|device| device.device_id == device_id)
+        {
+            return Err(WootingAnalogResult::DeviceDisconnected.into());
+        }
+        if let Some(index) = state.failing_reads.iter().position(|id| *id == device_id) {
+            state.failing_reads.remove(index);
+            return Err(WootingAnalogResult::Failure.into());
+        }
+        Ok(state
+            .device_frames
+            .entry(device_id)
+            .or_default()
+            .next(max_length))
+    }
+
+    fn set_device_event_callback(&mut self, callback: DeviceEventCallback) -> Result<()> {
+        self.state.lock().unwrap().event_callback = Some(callback);
+        Ok(())
+    }
+
+    fn clear_device_event_callback(&mut self) {
+        self.state.lock().unwrap().event_callback = None;
+    }
+
+    fn uninitialise(&mut self) {}
+}